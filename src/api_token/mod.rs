@@ -0,0 +1,353 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, WrapErr};
+
+use crate::{
+    cli::FlakeHubPushCli,
+    error::Error,
+    git_context::GitContext,
+    provider::{Authenticator, Provider, Repository},
+    revision_info::RevisionInfo,
+};
+
+/// Authenticating with a FlakeHub API token instead of an OIDC token, like from a workstation.
+pub(crate) struct ApiToken;
+
+impl ApiToken {
+    pub(crate) const NAME: &'static str = "token";
+}
+
+#[async_trait::async_trait]
+impl Provider for ApiToken {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    /// Tokens in a netrc file or `nix.conf` aren't evidence, since they're there for Nix on any machine. They're only
+    /// looked up once this environment is chosen.
+    fn detect(&self, cli: &FlakeHubPushCli) -> Vec<String> {
+        let mut evidence = crate::provider::set_variables(&["FLAKEHUB_PUSH_TOKEN"]);
+        if cli.token_file.0.is_some() {
+            evidence.push("`--token-file`".to_string());
+        }
+        evidence
+    }
+
+    async fn prepare(
+        &self,
+        cli: &FlakeHubPushCli,
+        _client: &reqwest::Client,
+        _repository: &Repository,
+        local_rev_info: RevisionInfo,
+    ) -> color_eyre::Result<(GitContext, Box<dyn Authenticator>)> {
+        // Read up front, so a missing token is reported before evaluating the flake
+        let authenticator = authenticator(cli).await?;
+        let git_ctx = GitContext::from_cli(cli, local_rev_info).await?;
+
+        Ok((git_ctx, authenticator))
+    }
+}
+
+/// Find the token, which unlike OIDC tokens doesn't depend on the repository.
+pub(crate) async fn authenticator(
+    cli: &FlakeHubPushCli,
+) -> color_eyre::Result<Box<dyn Authenticator>> {
+    let token = if let Some(path) = &cli.token_file.0 {
+        tokio::fs::read_to_string(path)
+            .await
+            .wrap_err_with(|| eyre!("Reading the FlakeHub token from {}", path.display()))?
+    } else if let Ok(token) = std::env::var("FLAKEHUB_PUSH_TOKEN") {
+        token
+    } else if let Some((token, source)) = find_configured_token(&cli.host) {
+        tracing::info!(
+            "Using the FlakeHub token for `{}` in {}",
+            cli.host.host_str().unwrap_or_default(),
+            source.display()
+        );
+        token
+    } else {
+        return Err(Error::MissingConfiguration(format!(
+            "No FlakeHub token found. Pass `--token-file`, set `FLAKEHUB_PUSH_TOKEN`, or add a token for `{}` to Nix's `netrc-file`, `~/.netrc`, or the `access-tokens` of `nix.conf`",
+            cli.host.host_str().unwrap_or_default()
+        )))?;
+    };
+
+    let token = token.trim().to_string();
+    if token.is_empty() {
+        return Err(Error::MissingConfiguration(
+            "The FlakeHub token is empty".to_string(),
+        ))?;
+    }
+    Ok(Box::new(StaticToken(token)))
+}
+
+/// A token which is used as-is. FlakeHub checks it when the push starts.
+struct StaticToken(String);
+
+#[async_trait::async_trait]
+impl Authenticator for StaticToken {
+    async fn bearer_token(&self) -> color_eyre::Result<String> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Find a token for FlakeHub at `host` in a netrc file or `nix.conf`, and which file it came from.
+fn find_configured_token(host: &url::Url) -> Option<(String, PathBuf)> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let nix_conf_dir = std::env::var_os("NIX_CONF_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/etc/nix"));
+
+    // User configuration takes precedence over the system's
+    let mut nix_confs: Vec<PathBuf> = match std::env::var("NIX_USER_CONF_FILES") {
+        Ok(files) => files.split(':').map(PathBuf::from).collect(),
+        Err(_) => std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home.as_ref().map(|home| home.join(".config")))
+            .map(|config| vec![config.join("nix").join("nix.conf")])
+            .unwrap_or_default(),
+    };
+    nix_confs.push(nix_conf_dir.join("nix.conf"));
+
+    let user_netrc = home.map(|home| home.join(".netrc"));
+    configured_token(
+        host,
+        &nix_confs,
+        &nix_conf_dir.join("netrc"),
+        user_netrc.as_deref(),
+    )
+}
+
+/// Find a token for FlakeHub at `host` in the netrc file Nix uses (its `netrc-file`, or `default_netrc`), in
+/// `user_netrc`, or in the `access-tokens` of `nix_confs`, which are ordered from most to least important.
+fn configured_token(
+    host: &url::Url,
+    nix_confs: &[PathBuf],
+    default_netrc: &Path,
+    user_netrc: Option<&Path>,
+) -> Option<(String, PathBuf)> {
+    let host = host.host_str()?;
+    // Tokens may be configured for the web frontend, like `flakehub.com` for `api.flakehub.com`
+    let hosts: Vec<&str> = std::iter::once(host)
+        .chain(host.strip_prefix("api."))
+        .collect();
+
+    let nix_confs: Vec<(&PathBuf, String)> = nix_confs
+        .iter()
+        .filter_map(|nix_conf| Some((nix_conf, read(nix_conf)?)))
+        .collect();
+
+    let nix_netrc = nix_confs
+        .iter()
+        .find_map(|(_, contents)| nix_conf_value(contents, "netrc-file"))
+        .map(PathBuf::from)
+        .unwrap_or_else(|| default_netrc.to_path_buf());
+    let netrcs = std::iter::once(nix_netrc).chain(user_netrc.map(Path::to_path_buf));
+    for netrc in netrcs {
+        if let Some(token) = read(&netrc)
+            .and_then(|contents| hosts.iter().find_map(|h| netrc_password(&contents, h)))
+        {
+            return Some((token, netrc));
+        }
+    }
+
+    nix_confs.into_iter().find_map(|(nix_conf, contents)| {
+        let token = hosts
+            .iter()
+            .find_map(|h| nix_conf_access_token(&contents, h))?;
+        Some((token, nix_conf.clone()))
+    })
+}
+
+fn read(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+}
+
+/// The `password` of the `machine` named `host` in a `.netrc`.
+///
+/// The `default` entry is ignored, since it's usually meant for some other service.
+fn netrc_password(contents: &str, host: &str) -> Option<String> {
+    let mut words = contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(str::split_whitespace);
+
+    let mut matched = false;
+    while let Some(word) = words.next() {
+        match word {
+            "machine" | "default" if matched => break,
+            "machine" => matched = words.next() == Some(host),
+            "password" => {
+                let password = words.next();
+                if matched {
+                    return password.map(ToString::to_string);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// The value of the setting `name` in a `nix.conf`, if it's set.
+fn nix_conf_value(contents: &str, name: &str) -> Option<String> {
+    // Later settings override earlier ones
+    contents
+        .lines()
+        .rev()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .filter_map(|line| line.split_once('='))
+        .find(|(setting, _)| setting.trim() == name)
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// The token for `host` in the `access-tokens` (or `extra-access-tokens`) of a `nix.conf`.
+fn nix_conf_access_token(contents: &str, host: &str) -> Option<String> {
+    let mut token = None;
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let Some((name, value)) = line.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        if name != "access-tokens" && name != "extra-access-tokens" {
+            continue;
+        }
+        // Later settings override earlier ones
+        if let Some(found) = value
+            .split_whitespace()
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(h, _)| *h == host)
+            .map(|(_, t)| t.to_string())
+        {
+            token = Some(found);
+        }
+    }
+    token
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser as _;
+
+    use super::{
+        configured_token, netrc_password, nix_conf_access_token, nix_conf_value, ApiToken,
+    };
+    use crate::{cli::FlakeHubPushCli, flakehub_auth_fake::LocalGitHub};
+
+    #[test]
+    fn netrc_passwords() {
+        let netrc = "\
+machine github.com login x password gh
+# a comment
+machine api.flakehub.com
+  login flakehub
+  password fh-token
+default login anon password fallback
+";
+        assert_eq!(
+            netrc_password(netrc, "api.flakehub.com").as_deref(),
+            Some("fh-token")
+        );
+        assert_eq!(netrc_password(netrc, "github.com").as_deref(), Some("gh"));
+        assert_eq!(netrc_password(netrc, "example.com"), None);
+        assert_eq!(netrc_password("machine a password b", "c"), None);
+    }
+
+    #[test]
+    fn nix_conf_access_tokens() {
+        let nix_conf = "\
+experimental-features = nix-command flakes
+access-tokens = github.com=gh flakehub.com=old # comment
+extra-access-tokens = flakehub.com=new
+";
+        assert_eq!(
+            nix_conf_access_token(nix_conf, "flakehub.com").as_deref(),
+            Some("new")
+        );
+        assert_eq!(
+            nix_conf_access_token(nix_conf, "github.com").as_deref(),
+            Some("gh")
+        );
+        assert_eq!(nix_conf_access_token(nix_conf, "gitlab.com"), None);
+    }
+
+    #[test]
+    fn nix_conf_values() {
+        let nix_conf = "\
+netrc-file = /etc/nix/old-netrc
+netrc-file = /etc/nix/netrc # comment
+";
+        assert_eq!(
+            nix_conf_value(nix_conf, "netrc-file").as_deref(),
+            Some("/etc/nix/netrc")
+        );
+        assert_eq!(nix_conf_value(nix_conf, "access-tokens"), None);
+    }
+
+    #[test]
+    fn configured_tokens() {
+        let host = url::Url::parse("https://api.flakehub.com").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let user_conf = dir.path().join("user.conf");
+        let system_conf = dir.path().join("system.conf");
+        let default_netrc = dir.path().join("netrc");
+        let custom_netrc = dir.path().join("custom-netrc");
+        let user_netrc = dir.path().join(".netrc");
+        let nix_confs = [user_conf.clone(), system_conf.clone()];
+        let find = || {
+            configured_token(&host, &nix_confs, &default_netrc, Some(&user_netrc))
+                .map(|(token, path)| (token, path.file_name().unwrap().to_owned()))
+        };
+        let found = |token: &str, file: &str| Some((token.to_string(), file.into()));
+
+        assert_eq!(find(), None);
+
+        std::fs::write(&system_conf, "access-tokens = flakehub.com=system-conf\n").unwrap();
+        assert_eq!(find(), found("system-conf", "system.conf"));
+        std::fs::write(&user_conf, "access-tokens = flakehub.com=user-conf\n").unwrap();
+        assert_eq!(find(), found("user-conf", "user.conf"));
+
+        std::fs::write(
+            &user_netrc,
+            "machine api.flakehub.com password user-netrc\n",
+        )
+        .unwrap();
+        assert_eq!(find(), found("user-netrc", ".netrc"));
+        std::fs::write(
+            &default_netrc,
+            "machine flakehub.com password default-netrc\n",
+        )
+        .unwrap();
+        assert_eq!(find(), found("default-netrc", "netrc"));
+
+        // Nix only reads the `netrc-file` which is configured, instead of its default one
+        std::fs::write(
+            &system_conf,
+            format!("netrc-file = {}\n", custom_netrc.display()),
+        )
+        .unwrap();
+        assert_eq!(find(), found("user-netrc", ".netrc"));
+        std::fs::write(
+            &custom_netrc,
+            "machine api.flakehub.com password custom-netrc\n",
+        )
+        .unwrap();
+        assert_eq!(find(), found("custom-netrc", "custom-netrc"));
+    }
+
+    #[test]
+    fn configured_tokens_dont_override_local_development() {
+        let cli = FlakeHubPushCli::parse_from(["flakehub-push", "--dev-jwt-issuer"]);
+
+        // Only these two, so environment variables from CI don't matter. Configured tokens aren't evidence for
+        // either, so where they're found doesn't either.
+        let detected = crate::provider::providers()
+            .into_iter()
+            .filter(|p| p.name() == ApiToken::NAME || p.name() == LocalGitHub::NAME)
+            .find(|p| !p.detect(&cli).is_empty())
+            .map(|p| p.name());
+        assert_eq!(detected, Some(LocalGitHub::NAME));
+    }
+}
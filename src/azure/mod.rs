@@ -0,0 +1,273 @@
+use std::path::PathBuf;
+
+use color_eyre::eyre::{eyre, WrapErr};
+
+use crate::{
+    build_http_client,
+    cli::FlakeHubPushCli,
+    git_context::GitContext,
+    provider::{Authenticator, Provider, Repository},
+    revision_info::RevisionInfo,
+};
+
+/// Request an OIDC token from Azure DevOps for the pipeline job running this, optionally scoped to a service
+/// connection.
+#[tracing::instrument(skip_all, fields(service_connection_id = ?service_connection_id))]
+pub(crate) async fn get_pipeline_bearer_token(
+    service_connection_id: Option<&str>,
+) -> color_eyre::Result<String> {
+    // https://learn.microsoft.com/en-us/rest/api/azure/devops/distributedtask/oidctoken/create
+    let oidc_request_uri = std::env::var("SYSTEM_OIDCREQUESTURI").wrap_err(
+        "No `SYSTEM_OIDCREQUESTURI` found, `flakehub-push` requires Azure DevOps to issue a JWT",
+    )?;
+    let access_token = std::env::var("SYSTEM_ACCESSTOKEN").wrap_err(
+        "No `SYSTEM_ACCESSTOKEN` found. Map it into the step's environment with `env: { SYSTEM_ACCESSTOKEN: $(System.AccessToken) }`",
+    )?;
+
+    request_oidc_token(&oidc_request_uri, &access_token, service_connection_id).await
+}
+
+/// Request an OIDC token from the Azure DevOps endpoint at `oidc_request_uri`, authenticating with the job's
+/// `access_token`.
+async fn request_oidc_token(
+    oidc_request_uri: &str,
+    access_token: &str,
+    service_connection_id: Option<&str>,
+) -> color_eyre::Result<String> {
+    let mut request_url = url::Url::parse(oidc_request_uri).wrap_err_with(|| {
+        eyre!("`SYSTEM_OIDCREQUESTURI` is not a valid URL: {oidc_request_uri}")
+    })?;
+    request_url
+        .query_pairs_mut()
+        .append_pair("api-version", "7.1");
+    if let Some(service_connection_id) = service_connection_id {
+        request_url
+            .query_pairs_mut()
+            .append_pair("serviceConnectionId", service_connection_id);
+    }
+
+    let client = build_http_client().build()?;
+    let response = client
+        .post(request_url)
+        .bearer_auth(access_token)
+        .json(&serde_json::json!({}))
+        .send()
+        .await
+        .wrap_err("Requesting an OIDC token from Azure DevOps")?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(eyre!(
+            "Azure DevOps responded to the OIDC token request with {status}: {}",
+            body.trim()
+        ));
+    }
+
+    let response_json: serde_json::Value = response
+        .json()
+        .await
+        .wrap_err("Getting JSON from the Azure DevOps OIDC token response")?;
+
+    let token = response_json
+        .get("oidcToken")
+        .and_then(serde_json::Value::as_str)
+        .ok_or_else(|| eyre!("Getting `oidcToken` from the Azure DevOps OIDC token response"))?;
+
+    Ok(token.to_string())
+}
+
+/// Azure Pipelines.
+pub(crate) struct AzurePipelines;
+
+#[async_trait::async_trait]
+impl Provider for AzurePipelines {
+    fn name(&self) -> &'static str {
+        "azure-pipelines"
+    }
+
+    fn detect(&self, _cli: &FlakeHubPushCli) -> Vec<String> {
+        crate::provider::set_variables(&["TF_BUILD"])
+    }
+
+    fn backfill(&self, cli: &mut FlakeHubPushCli) {
+        // https://learn.microsoft.com/en-us/azure/devops/pipelines/build/variables
+
+        if cli.git_root.0.is_none() {
+            let env_key = "BUILD_SOURCESDIRECTORY";
+            if let Ok(env_val) = std::env::var(env_key) {
+                tracing::debug!(git_root = %env_val, "Set via `${env_key}`");
+                cli.git_root.0 = Some(PathBuf::from(env_val));
+            }
+        }
+
+        if cli.repository.0.is_none() {
+            if let Ok(name) = std::env::var("BUILD_REPOSITORY_NAME") {
+                let repository = repository_name(
+                    std::env::var("BUILD_REPOSITORY_PROVIDER").ok().as_deref(),
+                    std::env::var("SYSTEM_TEAMPROJECT").ok().as_deref(),
+                    &name,
+                );
+                tracing::debug!(%repository, "Set via `$BUILD_REPOSITORY_NAME`");
+                cli.repository.0 = Some(repository);
+            }
+        }
+
+        if cli.rev.0.is_none() {
+            let env_key = "BUILD_SOURCEVERSION";
+            if let Some(env_val) = std::env::var(env_key).ok().filter(|v| !v.is_empty()) {
+                tracing::debug!(rev = %env_val, "Set via `${env_key}`");
+                cli.rev.0 = Some(env_val);
+            }
+        }
+
+        if cli.tag.0.is_none() {
+            let env_key = "BUILD_SOURCEBRANCH";
+            if let Some(env_val) = std::env::var(env_key)
+                .ok()
+                .and_then(|branch| branch.strip_prefix("refs/tags/").map(str::to_string))
+            {
+                tracing::debug!(tag = %env_val, "Set via `${env_key}`");
+                cli.tag.0 = Some(env_val);
+            }
+        }
+    }
+
+    async fn prepare(
+        &self,
+        cli: &FlakeHubPushCli,
+        _client: &reqwest::Client,
+        _repository: &Repository,
+        local_rev_info: RevisionInfo,
+    ) -> color_eyre::Result<(GitContext, Box<dyn Authenticator>)> {
+        let git_ctx = GitContext::from_cli(cli, local_rev_info).await?;
+
+        Ok((
+            git_ctx,
+            Box::new(PipelineToken {
+                service_connection_id: cli.azure_service_connection_id.0.clone(),
+            }),
+        ))
+    }
+}
+
+/// The `owner/name` of the repository being built. For GitHub repositories `BUILD_REPOSITORY_NAME` is that
+/// already, but Azure Repos (`TfsGit`) only use the name, so their project is the owner.
+fn repository_name(provider: Option<&str>, team_project: Option<&str>, name: &str) -> String {
+    match (provider, team_project) {
+        (Some("TfsGit"), Some(project)) if !project.is_empty() && !name.contains('/') => {
+            format!("{project}/{name}")
+        }
+        _ => name.to_string(),
+    }
+}
+
+/// A token requested from Azure DevOps for the pipeline job.
+struct PipelineToken {
+    service_connection_id: Option<String>,
+}
+
+#[async_trait::async_trait]
+impl Authenticator for PipelineToken {
+    async fn bearer_token(&self) -> color_eyre::Result<String> {
+        get_pipeline_bearer_token(self.service_connection_id.as_deref())
+            .await
+            .wrap_err("Getting upload bearer token from Azure Pipelines")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    use super::{repository_name, request_oidc_token};
+
+    /// A stand-in for the Azure DevOps OIDC token endpoint, which answers a single request with `status` and
+    /// `body`, and returns the request's line and headers.
+    async fn oidc_endpoint(
+        status: &'static str,
+        body: &'static str,
+    ) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!(
+            "http://{}/org/_apis/distributedtask/hubs/build/plans/plan/jobs/job/oidctoken",
+            listener.local_addr().unwrap()
+        );
+
+        let request = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let read = stream.read(&mut buf).await.unwrap();
+                assert!(read > 0, "the request ended before its headers");
+                request.extend_from_slice(&buf[..read]);
+            }
+
+            let response = format!(
+                "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request).into_owned()
+        });
+
+        (uri, request)
+    }
+
+    #[tokio::test]
+    async fn requests_a_token_for_the_service_connection() {
+        let (uri, request) = oidc_endpoint("200 OK", r#"{"oidcToken":"the-oidc-token"}"#).await;
+
+        let token = request_oidc_token(&uri, "job-access-token", Some("connection-id"))
+            .await
+            .unwrap();
+        assert_eq!(token, "the-oidc-token");
+
+        let request = request.await.unwrap();
+        let request_line = request.lines().next().unwrap();
+        assert!(request_line.starts_with("POST /org/_apis/"), "{request}");
+        assert!(request_line.contains("api-version=7.1"), "{request}");
+        assert!(
+            request_line.contains("serviceConnectionId=connection-id"),
+            "{request}"
+        );
+        assert!(
+            request
+                .to_lowercase()
+                .contains("authorization: bearer job-access-token"),
+            "{request}"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_rejected_token_requests() {
+        let (uri, request) = oidc_endpoint("401 Unauthorized", "job token expired").await;
+
+        let error = request_oidc_token(&uri, "job-access-token", None)
+            .await
+            .unwrap_err();
+        let error = error.to_string();
+        assert!(error.contains("401"), "{error}");
+        assert!(error.contains("job token expired"), "{error}");
+
+        let request = request.await.unwrap();
+        assert!(!request.contains("serviceConnectionId"), "{request}");
+    }
+
+    #[test]
+    fn azure_repos_are_owned_by_their_project() {
+        assert_eq!(
+            repository_name(Some("TfsGit"), Some("Fabrikam"), "fabrikam-flake"),
+            "Fabrikam/fabrikam-flake"
+        );
+        assert_eq!(
+            repository_name(Some("GitHub"), Some("Fabrikam"), "octo/flake"),
+            "octo/flake"
+        );
+        assert_eq!(
+            repository_name(Some("TfsGit"), None, "fabrikam-flake"),
+            "fabrikam-flake"
+        );
+    }
+}
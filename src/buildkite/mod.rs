@@ -0,0 +1,174 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use color_eyre::eyre::{eyre, WrapErr};
+
+use crate::{
+    cli::FlakeHubPushCli,
+    git_context::GitContext,
+    provider::{Authenticator, Provider, Repository},
+    revision_info::RevisionInfo,
+};
+
+/// Request an OIDC token for FlakeHub from the Buildkite agent running this job.
+#[tracing::instrument(skip_all, fields(audience = tracing::field::Empty))]
+pub(crate) async fn get_agent_bearer_token(host: &url::Url) -> color_eyre::Result<String> {
+    let span = tracing::Span::current();
+    let audience = host.host_str().ok_or_else(|| eyre!("`--host` must contain a valid host (eg `https://api.flakehub.com` contains `api.flakehub.com`)"))?;
+    span.record("audience", audience);
+
+    let output = tokio::process::Command::new("buildkite-agent")
+        .args(["oidc", "request-token", "--audience", audience])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .wrap_err("Failed to run `buildkite-agent oidc request-token`, is `buildkite-agent` on the `PATH`?")?;
+
+    if !output.status.success() {
+        return Err(eyre!(
+            "`buildkite-agent oidc request-token --audience {audience}` failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let token = String::from_utf8(output.stdout)
+        .wrap_err("`buildkite-agent oidc request-token` printed a token which isn't UTF-8")?;
+
+    Ok(token.trim().to_string())
+}
+
+/// The `owner/name` (or `group/subgroup/name`) path of a Git remote like `BUILDKITE_REPO`, which may be an
+/// `https://`/`ssh://` URL or an scp-like `git@github.com:owner/name.git`.
+pub(crate) fn repository_from_remote(remote: &str) -> Option<String> {
+    let path = match url::Url::parse(remote) {
+        Ok(url) if url.has_host() => url.path().to_string(),
+        _ => remote.split_once(':')?.1.to_string(),
+    };
+
+    let path = path.trim_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+
+    (path.contains('/')).then(|| path.to_string())
+}
+
+/// Buildkite.
+pub(crate) struct Buildkite;
+
+#[async_trait::async_trait]
+impl Provider for Buildkite {
+    fn name(&self) -> &'static str {
+        "buildkite"
+    }
+
+    fn detect(&self, _cli: &FlakeHubPushCli) -> Vec<String> {
+        crate::provider::set_variables(&["BUILDKITE"])
+    }
+
+    fn backfill(&self, cli: &mut FlakeHubPushCli) {
+        // https://buildkite.com/docs/pipelines/configure/environment-variables
+
+        if cli.git_root.0.is_none() {
+            let env_key = "BUILDKITE_BUILD_CHECKOUT_PATH";
+            if let Ok(env_val) = std::env::var(env_key) {
+                tracing::debug!(git_root = %env_val, "Set via `${env_key}`");
+                cli.git_root.0 = Some(PathBuf::from(env_val));
+            }
+        }
+
+        if cli.repository.0.is_none() {
+            let env_key = "BUILDKITE_REPO";
+            if let Some(env_val) = std::env::var(env_key)
+                .ok()
+                .and_then(|remote| crate::buildkite::repository_from_remote(&remote))
+            {
+                tracing::debug!(repository = %env_val, "Set via `${env_key}`");
+                cli.repository.0 = Some(env_val);
+            }
+        }
+
+        if cli.rev.0.is_none() {
+            let env_key = "BUILDKITE_COMMIT";
+            // Builds triggered without a specific commit use `HEAD`, which the local checkout resolves better
+            if let Some(env_val) = std::env::var(env_key)
+                .ok()
+                .filter(|v| !v.is_empty() && v != "HEAD")
+            {
+                tracing::debug!(rev = %env_val, "Set via `${env_key}`");
+                cli.rev.0 = Some(env_val);
+            }
+        }
+
+        if cli.tag.0.is_none() {
+            let env_key = "BUILDKITE_TAG";
+            if let Some(env_val) = std::env::var(env_key).ok().filter(|v| !v.is_empty()) {
+                tracing::debug!(tag = %env_val, "Set via `${env_key}`");
+                cli.tag.0 = Some(env_val);
+            }
+        }
+    }
+
+    async fn prepare(
+        &self,
+        cli: &FlakeHubPushCli,
+        _client: &reqwest::Client,
+        _repository: &Repository,
+        local_rev_info: RevisionInfo,
+    ) -> color_eyre::Result<(GitContext, Box<dyn Authenticator>)> {
+        let git_ctx = GitContext::from_cli(cli, local_rev_info).await?;
+
+        Ok((
+            git_ctx,
+            Box::new(AgentToken {
+                host: cli.host.clone(),
+            }),
+        ))
+    }
+}
+
+/// A token requested from the Buildkite agent running the job.
+struct AgentToken {
+    host: url::Url,
+}
+
+#[async_trait::async_trait]
+impl Authenticator for AgentToken {
+    async fn bearer_token(&self) -> color_eyre::Result<String> {
+        get_agent_bearer_token(&self.host)
+            .await
+            .wrap_err("Getting upload bearer token from Buildkite")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::repository_from_remote;
+
+    #[test]
+    fn repository_from_remotes() {
+        for (remote, expected) in [
+            (
+                "git@github.com:DeterminateSystems/flakehub-push.git",
+                Some("DeterminateSystems/flakehub-push"),
+            ),
+            (
+                "https://github.com/DeterminateSystems/flakehub-push.git",
+                Some("DeterminateSystems/flakehub-push"),
+            ),
+            (
+                "ssh://git@gitlab.com/group/subgroup/project",
+                Some("group/subgroup/project"),
+            ),
+            ("https://github.com/", None),
+            ("not a remote", None),
+        ] {
+            assert_eq!(
+                repository_from_remote(remote).as_deref(),
+                expected,
+                "{remote}"
+            );
+        }
+    }
+}
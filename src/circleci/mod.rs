@@ -0,0 +1,104 @@
+use std::path::PathBuf;
+
+use color_eyre::eyre::WrapErr;
+
+use crate::{
+    cli::FlakeHubPushCli,
+    git_context::GitContext,
+    provider::{Authenticator, Provider, Repository},
+    revision_info::RevisionInfo,
+};
+
+#[tracing::instrument(skip_all)]
+pub(crate) async fn get_job_bearer_token() -> color_eyre::Result<String> {
+    // Unlike GitHub, CircleCI injects the token into every job, with the organization ID as its audience
+
+    let token = std::env::var("CIRCLE_OIDC_TOKEN_V2").wrap_err(
+        "Failed to get a JWT from CircleCI. `CIRCLE_OIDC_TOKEN_V2` is only set in jobs which use a context.",
+    )?;
+
+    Ok(token)
+}
+
+/// CircleCI.
+pub(crate) struct CircleCI;
+
+#[async_trait::async_trait]
+impl Provider for CircleCI {
+    fn name(&self) -> &'static str {
+        "circleci"
+    }
+
+    fn detect(&self, _cli: &FlakeHubPushCli) -> Vec<String> {
+        crate::provider::set_variables(&["CIRCLECI"])
+    }
+
+    fn backfill(&self, cli: &mut FlakeHubPushCli) {
+        // https://circleci.com/docs/variables/#built-in-environment-variables
+
+        if cli.git_root.0.is_none() {
+            let env_key = "CIRCLE_WORKING_DIRECTORY";
+            if let Ok(env_val) = std::env::var(env_key) {
+                tracing::debug!(git_root = %env_val, "Set via `${env_key}`");
+                // This is usually `~/project`, which the shell would normally expand
+                let git_root = match (env_val.strip_prefix("~/"), std::env::var_os("HOME")) {
+                    (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+                    _ => PathBuf::from(env_val),
+                };
+                cli.git_root.0 = Some(git_root);
+            }
+        }
+
+        if cli.repository.0.is_none() {
+            if let (Ok(username), Ok(reponame)) = (
+                std::env::var("CIRCLE_PROJECT_USERNAME"),
+                std::env::var("CIRCLE_PROJECT_REPONAME"),
+            ) {
+                let env_val = format!("{username}/{reponame}");
+                tracing::debug!(repository = %env_val, "Set via `$CIRCLE_PROJECT_USERNAME` and `$CIRCLE_PROJECT_REPONAME`");
+                cli.repository.0 = Some(env_val);
+            }
+        }
+
+        if cli.rev.0.is_none() {
+            let env_key = "CIRCLE_SHA1";
+            if let Some(env_val) = std::env::var(env_key).ok().filter(|v| !v.is_empty()) {
+                tracing::debug!(rev = %env_val, "Set via `${env_key}`");
+                cli.rev.0 = Some(env_val);
+            }
+        }
+
+        // Branch builds don't have a tag, and need `--rolling` instead
+        if cli.tag.0.is_none() {
+            let env_key = "CIRCLE_TAG";
+            if let Some(env_val) = std::env::var(env_key).ok().filter(|v| !v.is_empty()) {
+                tracing::debug!(tag = %env_val, "Set via `${env_key}`");
+                cli.tag.0 = Some(env_val);
+            }
+        }
+    }
+
+    async fn prepare(
+        &self,
+        cli: &FlakeHubPushCli,
+        _client: &reqwest::Client,
+        _repository: &Repository,
+        local_rev_info: RevisionInfo,
+    ) -> color_eyre::Result<(GitContext, Box<dyn Authenticator>)> {
+        let git_ctx = GitContext::from_cli(cli, local_rev_info).await?;
+
+        Ok((git_ctx, Box::new(JobToken)))
+    }
+}
+
+/// The `CIRCLE_OIDC_TOKEN_V2` CircleCI injects into the job.
+struct JobToken;
+
+#[async_trait::async_trait]
+impl Authenticator for JobToken {
+    async fn bearer_token(&self) -> color_eyre::Result<String> {
+        get_job_bearer_token()
+            .await
+            .wrap_err("Getting upload bearer token from CircleCI")
+    }
+}
@@ -1,19 +1,43 @@
 mod instrumentation;
 
+pub(crate) use instrumentation::Telemetry;
+
 use std::path::{Path, PathBuf};
 use std::str::FromStr as _;
 
 use color_eyre::eyre::{eyre, Context as _, Result};
 
+use crate::error::Error;
+use crate::evaluation::{self, EvaluationScope};
 use crate::git_context::GitContext;
-use crate::push_context::ExecutionEnvironment;
+use crate::nix::Nix;
+use crate::provider::CiProvider;
 use crate::{Visibility, DEFAULT_ROLLING_PREFIX};
 
+#[derive(Debug, Clone, Copy, clap::Subcommand)]
+pub(crate) enum Command {
+    /// Check everything a push needs, like Nix, the Git checkout, the release version and authenticating to
+    /// FlakeHub, without publishing anything.
+    Doctor,
+    /// Acquire a token like a push would, and print what FlakeHub says about it, like which organizations and
+    /// flakes it can publish to.
+    Whoami {
+        /// Print JSON instead of a summary.
+        #[clap(long)]
+        json: bool,
+    },
+}
+
 #[derive(Debug, clap::Parser)]
 #[clap(version)]
 pub(crate) struct FlakeHubPushCli {
+    /// What to do instead of pushing. Options only a push uses, like `--visibility`, go before it.
+    #[clap(subcommand)]
+    pub(crate) command: Option<Command>,
+
     #[clap(
         long,
+        global = true,
         env = "FLAKEHUB_PUSH_HOST",
         default_value = "https://api.flakehub.com"
     )]
@@ -27,27 +51,27 @@ pub(crate) struct FlakeHubPushCli {
     pub(crate) visibility_alt: Option<crate::Visibility>,
 
     // Will also detect `GITHUB_REF_NAME`
-    #[clap(long, env = "FLAKEHUB_PUSH_TAG", value_parser = StringToNoneParser, default_value = "")]
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_TAG", value_parser = StringToNoneParser, default_value = "")]
     pub(crate) tag: OptionString,
-    #[clap(long, env = "FLAKEHUB_PUSH_REV", value_parser = StringToNoneParser, default_value = "")]
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_REV", value_parser = StringToNoneParser, default_value = "")]
     pub(crate) rev: OptionString,
-    #[clap(long, env = "FLAKEHUB_PUSH_ROLLING_MINOR", value_parser = U64ToNoneParser, default_value = "")]
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_ROLLING_MINOR", value_parser = U64ToNoneParser, default_value = "")]
     pub(crate) rolling_minor: OptionU64,
-    #[clap(long, env = "FLAKEHUB_PUSH_ROLLING", value_parser = EmptyBoolParser, default_value_t = false)]
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_ROLLING", value_parser = EmptyBoolParser, default_value_t = false)]
     pub(crate) rolling: bool,
     // Also detects `GITHUB_TOKEN`
-    #[clap(long, env = "FLAKEHUB_PUSH_GITHUB_TOKEN", value_parser = StringToNoneParser, default_value = "")]
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_GITHUB_TOKEN", value_parser = StringToNoneParser, default_value = "")]
     pub(crate) github_token: OptionString,
-    #[clap(long, env = "FLAKEHUB_PUSH_NAME", value_parser = StringToNoneParser, default_value = "")]
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_NAME", value_parser = StringToNoneParser, default_value = "")]
     pub(crate) name: OptionString,
     /// Will also detect `GITHUB_REPOSITORY`
-    #[clap(long, env = "FLAKEHUB_PUSH_REPOSITORY", value_parser = StringToNoneParser, default_value = "")]
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_REPOSITORY", value_parser = StringToNoneParser, default_value = "")]
     pub(crate) repository: OptionString,
     // Also detects `GITHUB_WORKSPACE`
-    #[clap(long, env = "FLAKEHUB_PUSH_DIRECTORY", value_parser = PathBufToNoneParser, default_value = "")]
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_DIRECTORY", value_parser = PathBufToNoneParser, default_value = "")]
     pub(crate) directory: OptionPathBuf,
     // Also detects `GITHUB_WORKSPACE`
-    #[clap(long, env = "FLAKEHUB_PUSH_GIT_ROOT", value_parser = PathBufToNoneParser, default_value = "")]
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_GIT_ROOT", value_parser = PathBufToNoneParser, default_value = "")]
     pub(crate) git_root: OptionPathBuf,
     // If the repository is mirrored via DeterminateSystems' mirror functionality
     //
@@ -55,10 +79,49 @@ pub(crate) struct FlakeHubPushCli {
     #[clap(long, env = "FLAKEHUB_PUSH_MIRROR", default_value_t = false)]
     pub(crate) mirror: bool,
 
+    /// A file holding the OIDC token to authenticate with, re-read each time it's needed (like a Kubernetes
+    /// projected service account token). Used outside GitHub Actions and GitLab CI.
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_OIDC_TOKEN_FILE", value_parser = PathBufToNoneParser, default_value = "", conflicts_with = "oidc_token_command")]
+    pub(crate) oidc_token_file: OptionPathBuf,
+
+    /// A shell command which prints the OIDC token to authenticate with (like a Vault or SPIFFE client).
+    /// Used outside GitHub Actions and GitLab CI.
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_OIDC_TOKEN_COMMAND", value_parser = StringToNoneParser, default_value = "")]
+    pub(crate) oidc_token_command: OptionString,
+
+    /// A file containing a FlakeHub API token to authenticate with, instead of an OIDC token. The token may also
+    /// be given in `FLAKEHUB_PUSH_TOKEN`, or found in Nix's `netrc-file`, `~/.netrc`, or the `access-tokens` of `nix.conf` with
+    /// `--ci-provider token`.
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_TOKEN_FILE", value_parser = PathBufToNoneParser, default_value = "")]
+    pub(crate) token_file: OptionPathBuf,
+
+    /// Print the claims of the OIDC token (decoded, but not verified) next to what FlakeHub expects, before
+    /// evaluating the flake. This also happens automatically when FlakeHub rejects the token.
+    #[clap(long, env = "FLAKEHUB_PUSH_EXPLAIN_TOKEN", value_parser = EmptyBoolParser, default_value_t = false)]
+    pub(crate) explain_token: bool,
+
+    /// The environment to run in, like `github`, `gitlab`, `generic` or `local`, instead of detecting it from
+    /// environment variables.
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_CI_PROVIDER", value_parser = CiProviderToNoneParser, default_value = "")]
+    pub(crate) ci_provider: OptionCiProvider,
+
+    /// The ID of the Azure DevOps service connection to scope the pipeline's OIDC token to, if any.
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_AZURE_SERVICE_CONNECTION_ID", value_parser = StringToNoneParser, default_value = "")]
+    pub(crate) azure_service_connection_id: OptionString,
+
     /// URL of a JWT mock server (like https://github.com/spectare/fakeidp) which can issue tokens.
-    #[clap(long)]
+    #[clap(long, global = true, conflicts_with = "dev_jwt_issuer")]
     pub(crate) jwt_issuer_uri: Option<String>,
 
+    /// Sign tokens with a key generated on startup instead of `jwt-issuer-uri`, serving its JWKS on a loopback port
+    /// for the duration of the push. Only useful with a local FlakeHub.
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_DEV_JWT_ISSUER", value_parser = EmptyBoolParser, default_value_t = false)]
+    pub(crate) dev_jwt_issuer: bool,
+
+    /// The port for `dev-jwt-issuer` to listen on, instead of an unused one.
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_DEV_JWT_ISSUER_PORT", value_parser = U64ToNoneParser, default_value = "")]
+    pub(crate) dev_jwt_issuer_port: OptionU64,
+
     /// User-supplied labels, merged with any associated with GitHub repository (if possible)
     #[clap(
         long,
@@ -82,6 +145,7 @@ pub(crate) struct FlakeHubPushCli {
     /// An SPDX identifier from https://spdx.org/licenses/, inferred from GitHub (if possible)
     #[clap(
         long,
+        global = true,
         env = "FLAKEHUB_PUSH_SPDX_EXPRESSION",
         value_parser = SpdxToNoneParser,
         default_value = ""
@@ -96,14 +160,10 @@ pub(crate) struct FlakeHubPushCli {
     )]
     pub(crate) error_on_conflict: bool,
 
-    /// Do less work on extremely large flakes.
-    ///
-    /// This flag is intended to limit the scope of evaluations which are too large to complete on one machine.
-    /// This flag should NOT be used to paper over evaluation errors across different architectures.
+    /// DEPRECATED: Please use `systems` and `outputs` instead.
     ///
-    /// Please do not turn this flag on without opening an issue to decide if it applies to your scenario.
-    ///
-    /// Note: the behavior of this flag could change at any time, please don't count on it for anything specific.
+    /// Do less work on extremely large flakes: only evaluate the current system (if `systems` is not already
+    /// set), and don't inspect the flake's outputs at all.
     #[clap(
       long,
       env = "FLAKEHUB_PUSH_MY_FLAKE_IS_TOO_BIG",
@@ -112,6 +172,98 @@ pub(crate) struct FlakeHubPushCli {
     )]
     pub(crate) my_flake_is_too_big: bool,
 
+    /// Only evaluate and inspect per-system outputs for these systems (e.g. `x86_64-linux,aarch64-darwin`).
+    ///
+    /// This is intended to limit the scope of evaluations which are too large to complete on one machine.
+    /// It should NOT be used to paper over evaluation errors across different architectures.
+    #[clap(
+        long,
+        env = "FLAKEHUB_PUSH_SYSTEMS",
+        use_value_delimiter = true,
+        value_delimiter = ','
+    )]
+    pub(crate) systems: Vec<String>,
+
+    /// Only evaluate and inspect these output categories (e.g. `packages,nixosModules`).
+    #[clap(
+        long,
+        env = "FLAKEHUB_PUSH_OUTPUTS",
+        use_value_delimiter = true,
+        value_delimiter = ','
+    )]
+    pub(crate) outputs: Vec<String>,
+
+    /// Run `nix flake check` before publishing, and refuse to publish if any check fails.
+    ///
+    /// The checks are run for the same systems as the evaluation check (see `systems`).
+    #[clap(long, env = "FLAKEHUB_PUSH_FLAKE_CHECK", value_parser = EmptyBoolParser, default_value_t = false)]
+    pub(crate) flake_check: bool,
+
+    /// Build the checks run by `flake-check`, instead of only evaluating them (`--no-build`).
+    #[clap(long, env = "FLAKEHUB_PUSH_FLAKE_CHECK_BUILD", value_parser = EmptyBoolParser, default_value_t = false)]
+    pub(crate) flake_check_build: bool,
+
+    /// The Nix binary to run, instead of the `nix` on `PATH`.
+    #[clap(
+        long,
+        global = true,
+        env = "FLAKEHUB_PUSH_NIX_BINARY",
+        default_value = "nix"
+    )]
+    pub(crate) nix_binary: PathBuf,
+
+    /// Extra arguments passed to every invocation of Nix (newline-separated in the environment variable).
+    #[clap(
+        long = "nix-arg",
+        global = true,
+        env = "FLAKEHUB_PUSH_NIX_ARGS",
+        value_delimiter = '\n',
+        allow_hyphen_values = true
+    )]
+    pub(crate) nix_args: Vec<String>,
+
+    /// Nix settings passed to every invocation of Nix, formatted like `name=value` (newline-separated in the environment variable).
+    #[clap(
+        long = "nix-option",
+        global = true,
+        env = "FLAKEHUB_PUSH_NIX_OPTIONS",
+        value_delimiter = '\n'
+    )]
+    pub(crate) nix_options: Vec<String>,
+
+    /// Accept the `nixConfig` of the flake being pushed (`--accept-flake-config`).
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_ACCEPT_FLAKE_CONFIG", value_parser = EmptyBoolParser, default_value_t = false)]
+    pub(crate) accept_flake_config: bool,
+
+    /// Run Nix with `--offline`, so it only uses what's already in the Nix store and caches.
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_OFFLINE", value_parser = EmptyBoolParser, default_value_t = false)]
+    pub(crate) offline: bool,
+
+    /// A file of whitespace-separated `host=token` pairs, used by Nix as `access-tokens` to fetch private inputs.
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_NIX_ACCESS_TOKENS_FILE", value_parser = PathBufToNoneParser, default_value = "")]
+    pub(crate) nix_access_tokens_file: OptionPathBuf,
+
+    /// A timeout (in seconds) for each phase which runs Nix, like fetching metadata or evaluating the flake. It
+    /// covers every command of the phase, like the evaluation of each system.
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_NIX_TIMEOUT", value_parser = U64ToNoneParser, default_value = "")]
+    pub(crate) nix_timeout: OptionU64,
+
+    /// Override `nix-timeout` for specific phases, formatted like `evaluation=600`.
+    ///
+    /// Phases are: metadata, prefetch, evaluation, lock-check, flake-check, inspect.
+    #[clap(
+        long = "nix-phase-timeout",
+        global = true,
+        env = "FLAKEHUB_PUSH_NIX_PHASE_TIMEOUTS",
+        use_value_delimiter = true,
+        value_delimiter = ','
+    )]
+    pub(crate) nix_phase_timeouts: Vec<String>,
+
+    /// The maximum number of flake outputs to evaluate concurrently, defaults to the number of CPUs.
+    #[clap(long, env = "FLAKEHUB_PUSH_EVAL_JOBS", value_parser = U64ToNoneParser, default_value = "")]
+    pub(crate) eval_jobs: OptionU64,
+
     #[clap(flatten)]
     pub instrumentation: instrumentation::Instrumentation,
 
@@ -123,6 +275,7 @@ pub(crate) struct FlakeHubPushCli {
     // mechanism to disable this behavior.
     #[clap(
         long,
+        global = true,
         env = "FLAKEHUB_PUSH_DISABLE_RENAME_SUBGROUPS",
         default_value_t = false
     )]
@@ -133,8 +286,13 @@ pub(crate) struct FlakeHubPushCli {
     pub(crate) dest_dir: OptionPathBuf,
 
     /// The GitHub GraphQL API URL base.
-    #[clap(long, env = "FLAKEHUB_GITHUB_GRAPHQL_URL", value_parser = StringToNoneParser, default_value = "")]
+    #[clap(long, global = true, env = "FLAKEHUB_GITHUB_GRAPHQL_URL", value_parser = StringToNoneParser, default_value = "")]
     pub(crate) github_graphql_url: OptionString,
+
+    /// The Gitea (or Forgejo) REST API URL base, like `https://codeberg.org/api/v1/`.
+    // Also detects `GITHUB_API_URL` and `GITHUB_SERVER_URL` in Gitea and Forgejo Actions
+    #[clap(long, global = true, env = "FLAKEHUB_PUSH_GITEA_API_URL")]
+    pub(crate) gitea_api_url: Option<url::Url>,
 }
 
 #[derive(Clone, Debug)]
@@ -280,91 +438,93 @@ impl clap::builder::TypedValueParser for U64ToNoneParser {
     }
 }
 
-impl FlakeHubPushCli {
-    pub(crate) fn backfill_from_github_env(&mut self) {
-        // https://docs.github.com/en/actions/learn-github-actions/variables
-
-        if self.git_root.0.is_none() {
-            let env_key = "GITHUB_WORKSPACE";
-            if let Ok(env_val) = std::env::var(env_key) {
-                tracing::debug!(git_root = %env_val, "Set via `${env_key}`");
-                self.git_root.0 = Some(PathBuf::from(env_val));
-            }
-        }
+#[derive(Clone, Debug)]
+pub struct OptionCiProvider(pub Option<CiProvider>);
 
-        if self.repository.0.is_none() {
-            let env_key = "GITHUB_REPOSITORY";
-            if let Ok(env_val) = std::env::var(env_key) {
-                tracing::debug!(repository = %env_val, "Set via `${env_key}`");
-                self.repository.0 = Some(env_val);
-            }
-        }
+#[derive(Clone)]
+struct CiProviderToNoneParser;
 
-        if self.tag.0.is_none() {
-            let env_key = "GITHUB_REF_NAME";
-            if let Ok(env_val) = std::env::var(env_key) {
-                tracing::debug!(repository = %env_val, "Set via `${env_key}`");
-                self.tag.0 = Some(env_val);
-            }
-        }
+impl clap::builder::TypedValueParser for CiProviderToNoneParser {
+    type Value = OptionCiProvider;
 
-        if self.github_graphql_url.0.is_none() {
-            let env_key = "GITHUB_GRAPHQL_URL";
-            if let Ok(env_val) = std::env::var(env_key) {
-                tracing::debug!(repository = %env_val, "Set via `${env_key}`");
-                self.github_graphql_url.0 = Some(env_val);
-            }
+    fn parse_ref(
+        &self,
+        cmd: &clap::Command,
+        arg: Option<&clap::Arg>,
+        value: &std::ffi::OsStr,
+    ) -> Result<Self::Value, clap::Error> {
+        if value.is_empty() {
+            return Ok(OptionCiProvider(None));
         }
+        let inner = clap::builder::EnumValueParser::<CiProvider>::new();
+        Ok(OptionCiProvider(Some(inner.parse_ref(cmd, arg, value)?)))
     }
 
-    pub(crate) fn backfill_from_gitlab_env(&mut self) {
-        // https://docs.gitlab.com/ee/ci/variables/predefined_variables.html
+    fn possible_values(
+        &self,
+    ) -> Option<Box<dyn Iterator<Item = clap::builder::PossibleValue> + '_>> {
+        Some(Box::new(
+            <CiProvider as clap::ValueEnum>::value_variants()
+                .iter()
+                .filter_map(clap::ValueEnum::to_possible_value),
+        ))
+    }
+}
 
-        if self.git_root.0.is_none() {
-            let env_key: &str = "CI_PROJECT_DIR";
-            if let Ok(env_val) = std::env::var(env_key) {
-                tracing::debug!(git_root = %env_val, "Set via `${env_key}`");
-                self.git_root.0 = Some(PathBuf::from(env_val));
-            }
+impl FlakeHubPushCli {
+    pub(crate) fn eval_jobs(&self) -> usize {
+        match self.eval_jobs.0 {
+            Some(jobs) => jobs as usize,
+            None => std::thread::available_parallelism()
+                .map(usize::from)
+                .unwrap_or(1),
         }
+    }
 
-        if self.repository.0.is_none() {
-            let env_key = "CI_PROJECT_ID";
-            if let Ok(env_val) = std::env::var(env_key) {
-                tracing::debug!(repository = %env_val, "Set via `${env_key}`");
-                self.repository.0 = Some(env_val);
+    pub(crate) async fn evaluation_scope(
+        &self,
+        nix: &Nix,
+        workflow_commands: bool,
+    ) -> Result<EvaluationScope> {
+        let mut systems: Vec<String> = self
+            .systems
+            .iter()
+            .filter(|v| !v.is_empty())
+            .cloned()
+            .collect();
+        let outputs: Vec<String> = self
+            .outputs
+            .iter()
+            .filter(|v| !v.is_empty())
+            .cloned()
+            .collect();
+
+        if self.my_flake_is_too_big {
+            let message = "`my-flake-is-too-big` is deprecated and will be removed in the future. Please use `systems` and `outputs` instead.";
+            tracing::warn!("{message}");
+
+            if workflow_commands {
+                println!("::warning::{message}");
             }
-        }
 
-        // TODO(review): this... isn't really a "tag" for github either, but I think maybe that's intentional?
-        if self.tag.0.is_none() {
-            let env_key = "CI_COMMIT_REF_NAME";
-            if let Ok(env_val) = std::env::var(env_key) {
-                tracing::debug!(repository = %env_val, "Set via `${env_key}`");
-                self.tag.0 = Some(env_val);
+            if systems.is_empty() {
+                systems.push(evaluation::current_system(nix).await?);
             }
         }
-    }
 
-    pub(crate) fn execution_environment(&self) -> ExecutionEnvironment {
-        if std::env::var("GITHUB_ACTION").ok().is_some() {
-            ExecutionEnvironment::GitHub
-        } else if std::env::var("GITLAB_CI").ok().is_some() {
-            ExecutionEnvironment::GitLab
-        } else if std::env::var("FLAKEHUB_PUSH_OIDC_TOKEN").ok().is_some() {
-            ExecutionEnvironment::Generic
-        } else {
-            ExecutionEnvironment::LocalGitHub
-        }
+        Ok(EvaluationScope {
+            systems: (!systems.is_empty()).then_some(systems),
+            outputs: (!outputs.is_empty()).then_some(outputs),
+        })
     }
 
     pub(crate) fn visibility(&self) -> Result<Visibility> {
         match (self.visibility_alt, self.visibility) {
             (Some(v), _) => Ok(v),
             (None, Some(v)) => Ok(v),
-            (None, None) =>  Err(color_eyre::eyre::eyre!(
-                "Could not determine the flake's desired visibility. Use `--visibility` to set this to one of the following: public, unlisted, private.",
-            )),
+            (None, None) =>  Err(Error::MissingConfiguration(
+                "Could not determine the flake's desired visibility. Use `--visibility` to set this to one of the following: public, unlisted, private.".to_string(),
+            ))?,
         }
     }
 
@@ -407,20 +567,20 @@ impl FlakeHubPushCli {
     pub(crate) fn release_version(&self, git_ctx: &GitContext) -> Result<String> {
         let rolling_prefix_or_tag = match (self.rolling_minor.0.as_ref(), &self.tag.0) {
             (Some(_), _) if !self.rolling => {
-                return Err(eyre!(
-                    "You must enable `rolling` to upload a release with a specific `rolling-minor`."
-                ));
+                return Err(Error::InvalidVersion(
+                    "You must enable `rolling` to upload a release with a specific `rolling-minor`.".to_string()
+                ))?;
             }
             (Some(minor), _) => format!("0.{minor}"),
             (None, _) if self.rolling => DEFAULT_ROLLING_PREFIX.to_string(),
             (None, Some(tag)) => {
                 let version_only = tag.strip_prefix('v').unwrap_or(tag);
                 // Ensure the version respects semver
-                semver::Version::from_str(version_only).wrap_err_with(|| eyre!("Failed to parse version `{tag}` as semver, see https://semver.org/ for specifications"))?;
+                semver::Version::from_str(version_only).map_err(|e| Error::InvalidVersion(format!("Failed to parse version `{tag}` as semver ({e}), see https://semver.org/ for specifications")))?;
                 tag.to_string()
             }
             (None, None) => {
-                return Err(eyre!("Could not determine tag or rolling minor version, `--tag`, `GITHUB_REF_NAME`, or `--rolling-minor` must be set"));
+                return Err(Error::MissingConfiguration("Could not determine tag or rolling minor version, `--tag`, `GITHUB_REF_NAME`, or `--rolling-minor` must be set".to_string()))?;
             }
         };
 
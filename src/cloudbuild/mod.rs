@@ -0,0 +1,133 @@
+use color_eyre::eyre::{eyre, WrapErr};
+
+use crate::{
+    build_http_client,
+    cli::FlakeHubPushCli,
+    git_context::GitContext,
+    provider::{Authenticator, Provider, Repository},
+    revision_info::RevisionInfo,
+};
+
+/// Fetch an identity token for FlakeHub from the metadata server of the machine running this Cloud Build step.
+#[tracing::instrument(skip_all, fields(audience = tracing::field::Empty))]
+pub(crate) async fn get_metadata_bearer_token(host: &url::Url) -> color_eyre::Result<String> {
+    let span = tracing::Span::current();
+    let audience = host.host_str().ok_or_else(|| eyre!("`--host` must contain a valid host (eg `https://api.flakehub.com` contains `api.flakehub.com`)"))?;
+    span.record("audience", audience);
+
+    // The same variable the Google Cloud client libraries use to reach a stand-in metadata server
+    let metadata_host = std::env::var("GCE_METADATA_HOST")
+        .unwrap_or_else(|_| "metadata.google.internal".to_string());
+    let mut request_url = url::Url::parse(&format!(
+        "http://{metadata_host}/computeMetadata/v1/instance/service-accounts/default/identity"
+    ))
+    .wrap_err_with(|| eyre!("`GCE_METADATA_HOST` is not a valid host: {metadata_host}"))?;
+    request_url
+        .query_pairs_mut()
+        .append_pair("audience", audience)
+        .append_pair("format", "full");
+
+    let client = build_http_client().build()?;
+    let response = client
+        .get(request_url)
+        .header("Metadata-Flavor", "Google")
+        .send()
+        .await
+        .wrap_err("Requesting an identity token from the metadata server")?;
+
+    let status = response.status();
+    let body = response
+        .text()
+        .await
+        .wrap_err("Reading the metadata server's identity token response")?;
+    if !status.is_success() {
+        return Err(eyre!(
+            "The metadata server responded to the identity token request with {status}: {}",
+            body.trim()
+        ));
+    }
+
+    let token = body.trim();
+    if token.is_empty() {
+        return Err(eyre!(
+            "The metadata server returned an empty identity token"
+        ));
+    }
+
+    Ok(token.to_string())
+}
+
+/// Google Cloud Build.
+pub(crate) struct CloudBuild;
+
+#[async_trait::async_trait]
+impl Provider for CloudBuild {
+    fn name(&self) -> &'static str {
+        "cloud-build"
+    }
+
+    fn detect(&self, _cli: &FlakeHubPushCli) -> Vec<String> {
+        // Cloud Build sets few variables of its own, but this one is set in every build step
+        crate::provider::set_variables(&["BUILDER_OUTPUT"])
+    }
+
+    fn backfill(&self, cli: &mut FlakeHubPushCli) {
+        // https://cloud.google.com/build/docs/configuring-builds/substitute-variable-values
+        // Substitutions aren't in the environment unless a step maps them in, like `env: ['COMMIT_SHA=$COMMIT_SHA']`
+
+        if cli.repository.0.is_none() {
+            let env_key = "REPO_FULL_NAME";
+            if let Some(env_val) = std::env::var(env_key).ok().filter(|v| !v.is_empty()) {
+                tracing::debug!(repository = %env_val, "Set via `${env_key}`");
+                cli.repository.0 = Some(env_val);
+            }
+        }
+
+        if cli.rev.0.is_none() {
+            let env_key = "COMMIT_SHA";
+            if let Some(env_val) = std::env::var(env_key).ok().filter(|v| !v.is_empty()) {
+                tracing::debug!(rev = %env_val, "Set via `${env_key}`");
+                cli.rev.0 = Some(env_val);
+            }
+        }
+
+        if cli.tag.0.is_none() {
+            let env_key = "TAG_NAME";
+            if let Some(env_val) = std::env::var(env_key).ok().filter(|v| !v.is_empty()) {
+                tracing::debug!(tag = %env_val, "Set via `${env_key}`");
+                cli.tag.0 = Some(env_val);
+            }
+        }
+    }
+
+    async fn prepare(
+        &self,
+        cli: &FlakeHubPushCli,
+        _client: &reqwest::Client,
+        _repository: &Repository,
+        local_rev_info: RevisionInfo,
+    ) -> color_eyre::Result<(GitContext, Box<dyn Authenticator>)> {
+        let git_ctx = GitContext::from_cli(cli, local_rev_info).await?;
+
+        Ok((
+            git_ctx,
+            Box::new(MetadataToken {
+                host: cli.host.clone(),
+            }),
+        ))
+    }
+}
+
+/// An identity token from the metadata server.
+struct MetadataToken {
+    host: url::Url,
+}
+
+#[async_trait::async_trait]
+impl Authenticator for MetadataToken {
+    async fn bearer_token(&self) -> color_eyre::Result<String> {
+        get_metadata_bearer_token(&self.host)
+            .await
+            .wrap_err("Getting upload bearer token from Cloud Build")
+    }
+}
@@ -0,0 +1,239 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+/// How long the tokens it mints are valid for.
+const TOKEN_LIFETIME_SECS: u64 = 10 * 60;
+
+/// How long to wait before accepting connections again after failing to.
+const ACCEPT_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// A minimal OIDC issuer for local development, which signs tokens with an ES256 key generated at startup and
+/// serves its JWKS on a loopback port, so a local FlakeHub can verify them.
+///
+/// It stops serving when dropped.
+pub(crate) struct DevJwtIssuer {
+    issuer_url: url::Url,
+    key_pair: EcdsaKeyPair,
+    key_id: String,
+    rng: SystemRandom,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl DevJwtIssuer {
+    /// Generate a key and start serving on `127.0.0.1:{port}`, or an unused port.
+    pub(crate) async fn start(port: Option<u16>) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+            .map_err(|e| eyre!("Generating a signing key: {e}"))?;
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .map_err(|e| eyre!("Loading the generated signing key: {e}"))?;
+        let key_id = uuid::Uuid::new_v4().to_string();
+
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", port.unwrap_or(0)))
+            .await
+            .wrap_err("Binding the development JWT issuer")?;
+        let address = listener.local_addr()?;
+        let issuer_url = url::Url::parse(&format!("http://{address}"))?;
+
+        // The uncompressed point is `0x04 || x || y`
+        let public_key = key_pair.public_key().as_ref();
+        let (x, y) = public_key[1..].split_at(32);
+        let jwks = serde_json::json!({
+            "keys": [{
+                "kty": "EC",
+                "crv": "P-256",
+                "alg": "ES256",
+                "use": "sig",
+                "kid": key_id,
+                "x": URL_SAFE_NO_PAD.encode(x),
+                "y": URL_SAFE_NO_PAD.encode(y),
+            }]
+        })
+        .to_string();
+        let openid_configuration = serde_json::json!({
+            "issuer": issuer_url.as_str().trim_end_matches('/'),
+            "jwks_uri": issuer_url.join(".well-known/jwks")?,
+            "id_token_signing_alg_values_supported": ["ES256"],
+        })
+        .to_string();
+
+        let server = tokio::spawn(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Like running out of file descriptors, which won't have changed right away
+                        tracing::warn!("Development JWT issuer failed to accept a connection: {e}");
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let jwks = jwks.clone();
+                let openid_configuration = openid_configuration.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, &openid_configuration, &jwks).await {
+                        tracing::debug!("Development JWT issuer failed to respond: {e}");
+                    }
+                });
+            }
+        });
+
+        tracing::info!(issuer = %issuer_url, "Started a development JWT issuer");
+
+        Ok(Self {
+            issuer_url,
+            key_pair,
+            key_id,
+            rng,
+            server,
+        })
+    }
+
+    /// The `iss` of the tokens it mints, which is also where it serves its OpenID configuration.
+    pub(crate) fn issuer(&self) -> &str {
+        self.issuer_url.as_str().trim_end_matches('/')
+    }
+
+    /// Sign `claims`, with the issuer and validity period filled in.
+    pub(crate) fn mint(&self, mut claims: github_actions_oidc_claims::Claims) -> Result<String> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        claims.iss = self.issuer().to_string();
+        claims.iat = now as f64;
+        claims.nbf = now as f64;
+        claims.exp = (now + TOKEN_LIFETIME_SECS) as f64;
+        claims.jti = uuid::Uuid::new_v4().to_string();
+
+        let header = serde_json::json!({
+            "alg": "ES256",
+            "typ": "JWT",
+            "kid": self.key_id,
+        });
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let signature = self
+            .key_pair
+            .sign(&self.rng, signing_input.as_bytes())
+            .map_err(|e| eyre!("Signing the development JWT: {e}"))?;
+
+        Ok(format!(
+            "{signing_input}.{}",
+            URL_SAFE_NO_PAD.encode(signature.as_ref())
+        ))
+    }
+}
+
+impl Drop for DevJwtIssuer {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// Answer a single HTTP request for the OpenID configuration or the JWKS.
+async fn serve(
+    mut stream: tokio::net::TcpStream,
+    openid_configuration: &str,
+    jwks: &str,
+) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 16 * 1024 {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let path = request
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or_default();
+    let (status, body) = match path {
+        "/.well-known/openid-configuration" => ("200 OK", openid_configuration),
+        "/.well-known/jwks" => ("200 OK", jwks),
+        _ => ("404 Not Found", "{}"),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+    use super::DevJwtIssuer;
+
+    #[tokio::test]
+    async fn minted_tokens_verify_against_the_served_jwks() {
+        let issuer = DevJwtIssuer::start(None).await.unwrap();
+        let token = issuer
+            .mint(github_actions_oidc_claims::Claims::make_dummy())
+            .unwrap();
+
+        let client = reqwest::Client::new();
+        let openid_configuration: serde_json::Value = client
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                issuer.issuer()
+            ))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(openid_configuration["issuer"], issuer.issuer());
+        let jwks: serde_json::Value = client
+            .get(openid_configuration["jwks_uri"].as_str().unwrap())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        let (signing_input, signature) = token.rsplit_once('.').unwrap();
+        let (header, claims) = signing_input.split_once('.').unwrap();
+        let decode = |part: &str| URL_SAFE_NO_PAD.decode(part).unwrap();
+        let header: serde_json::Value = serde_json::from_slice(&decode(header)).unwrap();
+        let claims: serde_json::Value = serde_json::from_slice(&decode(claims)).unwrap();
+        assert_eq!(header["alg"], "ES256");
+        assert_eq!(claims["iss"], issuer.issuer());
+
+        let key = jwks["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|key| key["kid"] == header["kid"])
+            .expect("the token's `kid` is in the JWKS");
+        assert_eq!(key["alg"], "ES256");
+        assert_eq!(key["crv"], "P-256");
+
+        // The uncompressed point is `0x04 || x || y`
+        let mut point = vec![0x04];
+        point.extend(decode(key["x"].as_str().unwrap()));
+        point.extend(decode(key["y"].as_str().unwrap()));
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+            .verify(signing_input.as_bytes(), &decode(signature))
+            .expect("the signature verifies against the JWKS");
+    }
+}
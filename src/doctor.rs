@@ -0,0 +1,433 @@
+use std::{fmt::Display, process::ExitCode};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+
+use crate::{
+    build_http_client,
+    cli::{FlakeHubPushCli, Telemetry},
+    error::Error,
+    flakehub_client::FlakeHubClient,
+    nix::{Nix, NixPhase},
+    provider::{Authenticator, Repository},
+    push_context::determine_names,
+    revision_info::RevisionInfo,
+    token_claims::{self, TokenClaims},
+};
+
+/// The oldest Nix a push works with. Flakes arrived in 2.4 and `nix flake check --all-systems` in 2.15, but the
+/// `inspect` flake is referenced through FlakeHub, whose URLs rely on the lockable tarball protocol from 2.17.
+const MINIMUM_NIX_VERSION: semver::Version = semver::Version::new(2, 17, 0);
+
+enum Outcome {
+    Pass(String),
+    /// Works, but may not do what's intended.
+    Warn(String),
+    Fail(String),
+    /// Not checked, since something it needs failed or isn't configured.
+    Skip(String),
+}
+
+struct Check {
+    name: &'static str,
+    outcome: Outcome,
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (mark, detail) = match &self.outcome {
+            Outcome::Pass(detail) => ("✓", detail),
+            Outcome::Warn(detail) => ("!", detail),
+            Outcome::Fail(detail) => ("✗", detail),
+            Outcome::Skip(detail) => ("-", detail),
+        };
+        // Continuation lines are indented under the check, like a token explanation
+        write!(
+            f,
+            "{mark} {}: {}",
+            self.name,
+            detail.replace('\n', "\n      ")
+        )
+    }
+}
+
+#[derive(Default)]
+struct Checklist(Vec<Check>);
+
+impl Checklist {
+    fn pass(&mut self, name: &'static str, detail: impl Into<String>) {
+        self.push(name, Outcome::Pass(detail.into()));
+    }
+
+    fn warn(&mut self, name: &'static str, detail: impl Into<String>) {
+        self.push(name, Outcome::Warn(detail.into()));
+    }
+
+    fn fail(&mut self, name: &'static str, error: impl Display) {
+        // The alternate form includes the causes, like why a request failed
+        self.push(name, Outcome::Fail(format!("{error:#}")));
+    }
+
+    fn skip(&mut self, name: &'static str, reason: impl Into<String>) {
+        self.push(name, Outcome::Skip(reason.into()));
+    }
+
+    fn push(&mut self, name: &'static str, outcome: Outcome) {
+        let check = Check { name, outcome };
+        tracing::debug!("{check}");
+        self.0.push(check);
+    }
+
+    /// Record the outcome of `result`, keeping its value for later checks.
+    fn record<T>(
+        &mut self,
+        name: &'static str,
+        result: Result<T>,
+        detail: impl FnOnce(&T) -> String,
+    ) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.pass(name, detail(&value));
+                Some(value)
+            }
+            Err(e) => {
+                self.fail(name, e);
+                None
+            }
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.0
+            .iter()
+            .any(|check| matches!(check.outcome, Outcome::Fail(_)))
+    }
+
+    /// Fail the run if any check failed; warnings and skipped checks don't.
+    fn exit_code(&self) -> ExitCode {
+        if self.failed() {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+/// Check everything a push needs, without publishing, and print a checklist of what would fail.
+pub(crate) async fn run(mut cli: FlakeHubPushCli, telemetry: &Telemetry) -> Result<ExitCode> {
+    let mut checks = Checklist::default();
+
+    check_nix(&mut checks, Nix::from_cli(&cli, telemetry)).await;
+
+    // Where this is running, which fills in the rest of the configuration
+    let provider = checks.record(
+        "Execution environment",
+        crate::provider::detect(&cli),
+        |provider| format!("`{}`", provider.name()),
+    );
+    if let Some(provider) = &provider {
+        provider.backfill(&mut cli);
+    }
+
+    let repository = checks.record("Repository", repository(&cli), |repository: &Repository| {
+        format!("`{}`", repository.full_name)
+    });
+
+    // Git
+    let local_rev_info = match git_root(&cli) {
+        Ok((git_root, rev_info, shallow)) => {
+            let detail = format!("{} at {}", git_root.display(), rev_info.revision);
+            if shallow {
+                checks.warn("Git root", format!("{detail} is a shallow clone, so rolling versions only count its commits correctly where the count comes from the provider's API (GitHub, Gitea); fetch the full history otherwise"));
+            } else {
+                checks.pass("Git root", detail);
+            }
+            Some(rev_info)
+        }
+        Err(e) => {
+            checks.fail("Git root", e);
+            None
+        }
+    };
+
+    let client = build_http_client().build()?;
+
+    // Fetching the details queries the provider's API, like GitHub's GraphQL API, where it has one
+    let prepared = match (&provider, &repository, local_rev_info) {
+        (Some(provider), Some(repository), Some(local_rev_info)) => checks.record(
+            "Repository details",
+            provider
+                .prepare(&cli, &client, repository, local_rev_info)
+                .await,
+            |(git_ctx, _)| match git_ctx.revision_info.commit_count {
+                Some(commit_count) => format!(
+                    "`{}` has {commit_count} commits, from the `{}` environment",
+                    repository.full_name,
+                    provider.name()
+                ),
+                None => format!("from the `{}` environment", provider.name()),
+            },
+        ),
+        _ => {
+            checks.skip(
+                "Repository details",
+                "needs the execution environment, the repository and the Git root",
+            );
+            None
+        }
+    };
+
+    match &prepared {
+        Some((git_ctx, _)) => {
+            checks.record("Release version", cli.release_version(git_ctx), |version| {
+                format!("`{version}`")
+            });
+        }
+        None => checks.skip("Release version", "needs the repository details"),
+    }
+
+    // FlakeHub
+    match prepared {
+        Some((_, authenticator)) => {
+            check_token(&mut checks, &cli, authenticator, telemetry).await?
+        }
+        None => {
+            checks.skip("Token", "needs the repository details");
+            checks.skip("FlakeHub token status", "needs a token");
+        }
+    }
+
+    println!("flakehub-push doctor:");
+    for check in &checks.0 {
+        println!("  {check}");
+    }
+
+    Ok(checks.exit_code())
+}
+
+/// Check that Nix is configured, installed and has flakes enabled, skipping what can't be checked.
+async fn check_nix(checks: &mut Checklist, nix: Result<Nix>) {
+    let nix = checks.record("Nix configuration", nix, |_| "valid".to_string());
+    let nix_version = match &nix {
+        Some(nix) => checks.record("Nix installed", nix_version(nix).await, |version| {
+            format!("{version} (at least {MINIMUM_NIX_VERSION} is required)")
+        }),
+        None => {
+            checks.skip("Nix installed", "the Nix configuration is invalid");
+            None
+        }
+    };
+    match (&nix, nix_version) {
+        (Some(nix), Some(_)) => {
+            checks.record("Flakes enabled", flakes_enabled(nix).await, |_| {
+                "yes".to_string()
+            });
+        }
+        _ => checks.skip("Flakes enabled", "Nix isn't usable"),
+    }
+}
+
+async fn check_token(
+    checks: &mut Checklist,
+    cli: &FlakeHubPushCli,
+    authenticator: Box<dyn Authenticator>,
+    telemetry: &Telemetry,
+) -> Result<()> {
+    let fhclient = FlakeHubClient::new(cli.host.clone(), authenticator, telemetry)?;
+
+    let token = checks.record("Token", fhclient.bearer_token().await, |token| {
+        match TokenClaims::decode_unverified(token) {
+            Ok(claims) => match claims.expires_in() {
+                Some(expires_in) => {
+                    format!("an OIDC token which expires in {}s", expires_in.as_secs())
+                }
+                None => "an OIDC token".to_string(),
+            },
+            Err(_) => "a FlakeHub API token".to_string(),
+        }
+    });
+    let Some(token) = token else {
+        checks.skip("FlakeHub token status", "needs a token");
+        return Ok(());
+    };
+
+    match fhclient.token_status().await {
+        Ok(response) => match FlakeHubClient::check_token_status(response).await {
+            Ok(_) => checks.pass("FlakeHub token status", format!("accepted by {}", cli.host)),
+            Err(e @ Error::Unauthorized(_)) => checks.fail(
+                "FlakeHub token status",
+                format!("{e}\n{}", token_claims::explain(&token, &cli.host)),
+            ),
+            Err(e) => checks.fail("FlakeHub token status", e),
+        },
+        Err(e) => checks.fail("FlakeHub token status", e),
+    }
+
+    Ok(())
+}
+
+fn repository(cli: &FlakeHubPushCli) -> Result<Repository> {
+    let Some(repository) = &cli.repository.0 else {
+        return Err(Error::MissingConfiguration("Could not determine repository name, pass `--repository` formatted like `determinatesystems/flakehub-push`".to_string()))?;
+    };
+    let (_, owner, name) = determine_names(&cli.name.0, repository, cli.disable_rename_subgroups)?;
+    Ok(Repository {
+        full_name: repository.clone(),
+        owner,
+        name,
+    })
+}
+
+fn git_root(cli: &FlakeHubPushCli) -> Result<(std::path::PathBuf, RevisionInfo, bool)> {
+    let git_root = cli.resolve_local_git_root()?;
+    let rev_info = RevisionInfo::from_git_root(&git_root)?;
+    let shallow = gix::open(&git_root)
+        .wrap_err("Opening the Git repository")?
+        .is_shallow();
+    Ok((git_root, rev_info, shallow))
+}
+
+async fn nix_version(nix: &Nix) -> Result<semver::Version> {
+    let output = nix
+        .output(NixPhase::Metadata, nix.command().arg("--version"))
+        .await?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "`nix --version` failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = parse_nix_version(&stdout)
+        .ok_or_else(|| eyre!("Could not find the version in `{}`", stdout.trim()))?;
+    if version < MINIMUM_NIX_VERSION {
+        return Err(eyre!(
+            "Nix {version} is too old, at least {MINIMUM_NIX_VERSION} is required"
+        ));
+    }
+
+    Ok(version)
+}
+
+/// The version of Nix in the output of `nix --version`, like `nix (Nix) 2.18.1` or `nix (Determinate Nix 3.6.0) 2.29.0`.
+fn parse_nix_version(output: &str) -> Option<semver::Version> {
+    let version = output.split_whitespace().last()?;
+    // Ignore suffixes like `pre20240101_abcdef`
+    let mut components = version
+        .split(|c: char| !c.is_ascii_digit())
+        .take_while(|c| !c.is_empty())
+        .take(3)
+        .map(|c| c.parse::<u64>().ok());
+    let major = components.next()??;
+    let minor = components.next().flatten().unwrap_or(0);
+    let patch = components.next().flatten().unwrap_or(0);
+    Some(semver::Version::new(major, minor, patch))
+}
+
+/// `builtins.getFlake` only exists when the `flakes` feature is enabled.
+async fn flakes_enabled(nix: &Nix) -> Result<()> {
+    let output = nix
+        .output(
+            NixPhase::Metadata,
+            nix.command()
+                .arg("eval")
+                .arg("--expr")
+                .arg("builtins ? getFlake"),
+        )
+        .await?;
+    if !output.status.success() {
+        return Err(eyre!("{}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    match String::from_utf8_lossy(&output.stdout).trim() {
+        "true" => Ok(()),
+        _ => Err(eyre!(
+            "The `flakes` experimental feature is disabled, add it to `experimental-features` in `nix.conf`"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::ExitCode;
+
+    use color_eyre::eyre::eyre;
+
+    use super::{check_nix, parse_nix_version, Checklist, Outcome};
+
+    fn outcomes(checks: &Checklist) -> Vec<(&'static str, &'static str)> {
+        checks
+            .0
+            .iter()
+            .map(|check| {
+                let outcome = match check.outcome {
+                    Outcome::Pass(_) => "pass",
+                    Outcome::Warn(_) => "warn",
+                    Outcome::Fail(_) => "fail",
+                    Outcome::Skip(_) => "skip",
+                };
+                (check.name, outcome)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn failed_checks_skip_what_depends_on_them() {
+        let mut checks = Checklist::default();
+        check_nix(&mut checks, Err(eyre!("`--nix-timeout` is invalid"))).await;
+
+        assert_eq!(
+            outcomes(&checks),
+            [
+                ("Nix configuration", "fail"),
+                ("Nix installed", "skip"),
+                ("Flakes enabled", "skip"),
+            ]
+        );
+        assert_eq!(checks.exit_code(), ExitCode::FAILURE);
+    }
+
+    #[test]
+    fn any_failure_fails_the_run() {
+        let mut checks = Checklist::default();
+        checks.pass("Repository", "`a/b`");
+        checks.skip("Release version", "needs the repository details");
+        assert_eq!(checks.exit_code(), ExitCode::SUCCESS);
+
+        let recorded = checks.record(
+            "Git root",
+            Err::<(), _>(eyre!("not a Git repository")),
+            |_| unreachable!(),
+        );
+        assert!(recorded.is_none());
+        assert!(checks.failed());
+        assert_eq!(checks.exit_code(), ExitCode::FAILURE);
+    }
+
+    #[test]
+    fn warnings_alone_pass() {
+        let mut checks = Checklist::default();
+        checks.pass("Repository", "`a/b`");
+        checks.warn("Git root", "a shallow clone");
+        checks.warn("Token", "expires in 2 minutes");
+
+        assert!(!checks.failed());
+        assert_eq!(checks.exit_code(), ExitCode::SUCCESS);
+    }
+
+    #[test]
+    fn nix_versions() {
+        let version = |output| parse_nix_version(output).map(|v| v.to_string());
+        assert_eq!(version("nix (Nix) 2.18.1\n").as_deref(), Some("2.18.1"));
+        assert_eq!(
+            version("nix (Determinate Nix 3.6.0) 2.29.0").as_deref(),
+            Some("2.29.0")
+        );
+        assert_eq!(
+            version("nix (Nix) 2.24.0pre20240101_abcdef").as_deref(),
+            Some("2.24.0")
+        );
+        assert_eq!(version("nix (Nix) 2.4").as_deref(), Some("2.4.0"));
+        assert_eq!(version("not nix"), None);
+    }
+}
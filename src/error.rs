@@ -1,3 +1,7 @@
+use std::process::ExitCode;
+
+use crate::github_actions;
+
 #[derive(Debug, thiserror::Error)]
 pub(crate) enum Error {
     /// Unauthorized, with a single line message detailing the nature of the problem.
@@ -10,26 +14,130 @@ pub(crate) enum Error {
     },
     #[error("Bad request: {0}")]
     BadRequest(String),
+    /// FlakeHub couldn't check the token, for a reason other than rejecting it.
+    #[error("Checking the token status failed: {0}")]
+    TokenStatus(String),
+    #[error("Interrupted by {0}")]
+    Interrupted(String),
+    #[error("Evaluation failed: {0}")]
+    EvaluationFailed(String),
+    #[error("`nix flake check` failed: {0}")]
+    FlakeCheckFailed(String),
+    /// The `flake.lock` is out of date with the inputs of `flake.nix`.
+    #[error("The flake.lock does not match flake.nix: {0}")]
+    LockDrift(String),
+    #[error("Uploading the release tarball to S3 failed: {0}")]
+    S3Upload(String),
+    #[error("Publishing the release failed: {0}")]
+    Publish(String),
+    #[error("GitHub API request failed: {0}")]
+    GitHubApi(String),
+    #[error("Gitea API request failed: {0}")]
+    GiteaApi(String),
+    #[error("Invalid version: {0}")]
+    InvalidVersion(String),
+    #[error("Missing configuration: {0}")]
+    MissingConfiguration(String),
 }
 
 impl Error {
     pub(crate) fn should_suggest_issue(&self) -> bool {
         match self {
-            Self::Unauthorized(_) | Self::Conflict { .. } | Self::BadRequest(_) => false,
+            Self::Unauthorized(_)
+            | Self::Conflict { .. }
+            | Self::BadRequest(_)
+            | Self::TokenStatus(_)
+            | Self::Interrupted(_)
+            | Self::EvaluationFailed(_)
+            | Self::FlakeCheckFailed(_)
+            | Self::LockDrift(_)
+            | Self::S3Upload(_)
+            | Self::Publish(_)
+            | Self::GitHubApi(_)
+            | Self::GiteaApi(_)
+            | Self::InvalidVersion(_)
+            | Self::MissingConfiguration(_) => false,
         }
     }
 
-    /// Output a Github Actions annotation command if desired.
-    // Note: These may only be one line! Any further lines will not be printed!
-    pub(crate) fn maybe_github_actions_annotation(&self) {
-        if std::env::var("GITHUB_ACTIONS").is_ok() {
-            match self {
-                Error::Unauthorized(message) => {
-                    println!("::error title=Unauthorized::{message}")
-                }
-                Error::Conflict { .. } => println!("::error title=Conflict::{self}"),
-                Error::BadRequest(_) => println!("::error title=BadRequest::{self}"),
-            }
+    /// The process exit code for this error. These are stable, so wrappers can branch on them.
+    pub(crate) fn exit_code(&self) -> u8 {
+        match self {
+            Self::Unauthorized(_) => 10,
+            Self::Conflict { .. } => 11,
+            Self::BadRequest(_) => 12,
+            Self::TokenStatus(_) => 13,
+            Self::EvaluationFailed(_) => 20,
+            Self::FlakeCheckFailed(_) => 21,
+            Self::LockDrift(_) => 22,
+            Self::S3Upload(_) => 30,
+            Self::Publish(_) => 31,
+            Self::GitHubApi(_) => 40,
+            Self::GiteaApi(_) => 41,
+            Self::InvalidVersion(_) => 50,
+            Self::MissingConfiguration(_) => 51,
+            Self::Interrupted(_) => 130,
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Self::Unauthorized(_) => "Unauthorized",
+            Self::Conflict { .. } => "Conflict",
+            Self::BadRequest(_) => "BadRequest",
+            Self::TokenStatus(_) => "Token status check failed",
+            Self::Interrupted(_) => "Interrupted",
+            Self::EvaluationFailed(_) => "Evaluation failed",
+            Self::FlakeCheckFailed(_) => "Flake check failed",
+            Self::LockDrift(_) => "Lock file out of date",
+            Self::S3Upload(_) => "S3 upload failed",
+            Self::Publish(_) => "Publish failed",
+            Self::GitHubApi(_) => "GitHub API error",
+            Self::GiteaApi(_) => "Gitea API error",
+            Self::InvalidVersion(_) => "Invalid version",
+            Self::MissingConfiguration(_) => "Missing configuration",
         }
     }
+
+    /// Output a Github Actions annotation command.
+    // Note: These may only be one line! Any further lines will not be printed!
+    pub(crate) fn github_actions_annotation(&self) {
+        let message = match self {
+            Error::Unauthorized(message) => message.clone(),
+            _ => self.to_string(),
+        };
+        let message = message.lines().next().unwrap_or_default();
+        println!(
+            "::error title={}::{}",
+            github_actions::escape_property(self.title()),
+            github_actions::escape_data(message)
+        );
+    }
+}
+
+/// Find the [`Error`] behind `report`, whether it was returned directly or wrapped in context.
+pub(crate) fn find(report: &color_eyre::eyre::Report) -> Option<&Error> {
+    report
+        .downcast_ref::<Error>()
+        .or_else(|| report.chain().find_map(|e| e.downcast_ref::<Error>()))
+}
+
+/// The exit code for a failed run: the code of its [`Error`], [`EXIT_NIX_TIMED_OUT`] if Nix timed out, or 1.
+pub(crate) fn exit_code(report: &color_eyre::eyre::Report) -> ExitCode {
+    if let Some(error) = find(report) {
+        return ExitCode::from(error.exit_code());
+    }
+
+    let timed_out = report.downcast_ref::<crate::nix::Error>().is_some()
+        || report
+            .chain()
+            .any(|e| e.downcast_ref::<crate::nix::Error>().is_some());
+    if timed_out {
+        return ExitCode::from(EXIT_NIX_TIMED_OUT);
+    }
+
+    ExitCode::FAILURE
 }
+
+/// The exit code when a Nix subprocess exceeded its timeout.
+pub(crate) const EXIT_NIX_TIMED_OUT: u8 = 23;
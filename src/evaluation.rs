@@ -0,0 +1,563 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use regex::Regex;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+use crate::github_actions;
+use crate::nix::{Nix, NixPhase};
+
+/// Outputs which are keyed by system, like `packages.x86_64-linux.default`.
+pub(crate) const PER_SYSTEM_OUTPUTS: &[&str] = &[
+    "apps",
+    "checks",
+    "devShells",
+    "formatter",
+    "legacyPackages",
+    "packages",
+    // Deprecated, but still seen in the wild
+    "defaultApp",
+    "defaultPackage",
+    "devShell",
+];
+
+/// Outputs which are enumerated, but never evaluated (this matches `nix flake show`).
+const UNEVALUATED_OUTPUTS: &[&str] = &["legacyPackages"];
+
+/// Lists the top level outputs of the flake, and the systems of per-system outputs.
+///
+/// Takes the flake reference and the list of per-system output names as arguments.
+const LIST_OUTPUTS_EXPR: &str = r#"
+flakeRef: perSystemOutputs:
+let
+  flake = builtins.getFlake flakeRef;
+in
+builtins.mapAttrs
+  (name: value: if builtins.elem name perSystemOutputs then builtins.attrNames value else null)
+  flake.outputs
+"#;
+
+/// Forces the name of every derivation (or the type of every other value) in a per-system output.
+const PER_SYSTEM_APPLY: &str = r#"
+output:
+let
+  check = value: if (value.type or null) == "derivation" then value.name else value.type or null;
+in
+if (output.type or null) == "derivation" then check output else builtins.mapAttrs (name: check) output
+"#;
+
+/// Forces the attribute names of an output which is not keyed by system.
+const OTHER_APPLY: &str =
+    "output: if builtins.isAttrs output then builtins.attrNames output else builtins.typeOf output";
+
+/// Filters the output of the `inspect` flake down to the outputs and systems allowed by an [`EvaluationScope`].
+///
+/// Takes the allowed systems and outputs (or `null` for all of them) and the list of per-system output names as
+/// arguments. Nix is lazy, so anything filtered out here is never evaluated.
+const INSPECT_FILTER_EXPR: &str = r#"
+systems: outputs: perSystemOutputs: contents:
+let
+  excluded = allowed: names: if allowed == null then [ ] else builtins.filter (name: !builtins.elem name allowed) names;
+  filterSystems = name: item:
+    if builtins.elem name perSystemOutputs && item ? children
+    then item // { children = builtins.removeAttrs item.children (excluded systems (builtins.attrNames item.children)); }
+    else item;
+in
+contents // {
+  inventory = builtins.mapAttrs filterSystems
+    (builtins.removeAttrs contents.inventory (excluded outputs (builtins.attrNames contents.inventory)));
+}
+"#;
+
+/// Restricts which systems and output categories are evaluated and inspected.
+///
+/// This is recorded in the release metadata, so FlakeHub knows which parts of the flake were checked.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct EvaluationScope {
+    /// The systems which were evaluated, or all of them if `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) systems: Option<Vec<String>>,
+    /// The output categories (like `packages`) which were evaluated, or all of them if `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) outputs: Option<Vec<String>>,
+}
+
+impl EvaluationScope {
+    pub(crate) fn is_restricted(&self) -> bool {
+        self.systems.is_some() || self.outputs.is_some()
+    }
+
+    fn includes_system(&self, system: &str) -> bool {
+        self.systems
+            .as_ref()
+            .is_none_or(|systems| systems.iter().any(|s| s == system))
+    }
+
+    fn includes_output(&self, output: &str) -> bool {
+        self.outputs
+            .as_ref()
+            .is_none_or(|outputs| outputs.iter().any(|o| o == output))
+    }
+
+    /// An expression suitable for `nix eval --apply` on the `inspect` flake's output.
+    pub(crate) fn inspect_filter_expr(&self) -> String {
+        format!(
+            "({INSPECT_FILTER_EXPR}) {} {} {}",
+            nix_optional_list(self.systems.as_deref()),
+            nix_optional_list(self.outputs.as_deref()),
+            nix_list(PER_SYSTEM_OUTPUTS),
+        )
+    }
+
+    fn notes(&self) -> Vec<String> {
+        let mut notes = Vec::new();
+        if let Some(systems) = &self.systems {
+            notes.push(format!(
+                "Only the following systems were evaluated: {}",
+                systems.join(", ")
+            ));
+        }
+        if let Some(outputs) = &self.outputs {
+            notes.push(format!(
+                "Only the following outputs were evaluated: {}",
+                outputs.join(", ")
+            ));
+        }
+        notes
+    }
+}
+
+/// A single unit of evaluation: a top level output, optionally narrowed to one system.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct EvaluationTarget {
+    pub(crate) output: String,
+    pub(crate) system: Option<String>,
+}
+
+impl EvaluationTarget {
+    fn attr_path(&self) -> String {
+        match &self.system {
+            Some(system) => format!("\"{}\".\"{}\"", self.output, system),
+            None => format!("\"{}\"", self.output),
+        }
+    }
+}
+
+impl Display for EvaluationTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.system {
+            Some(system) => write!(f, "{}.{}", self.output, system),
+            None => write!(f, "{}", self.output),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct EvaluationFailure {
+    pub(crate) target: EvaluationTarget,
+    pub(crate) command: String,
+    pub(crate) status: Option<i32>,
+    pub(crate) stderr: String,
+}
+
+impl EvaluationFailure {
+    /// The `error: ...` portion of the Nix output, without the preceding trace.
+    pub(crate) fn summary(&self) -> &str {
+        let stderr = self.stderr.trim();
+        match stderr.rfind("error:") {
+            Some(index) => &stderr[index..],
+            None => stderr,
+        }
+    }
+}
+
+/// Where in the repository an evaluation error happened, per the trace Nix printed.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ErrorLocation {
+    /// Relative to the root of the repository.
+    pub(crate) file: PathBuf,
+    pub(crate) line: u32,
+    pub(crate) col: u32,
+}
+
+/// Find the innermost frame of the trace in `stderr` which is a file of the flake's own source, in `source_root`.
+///
+/// Nix may refer to the source by a different store path than `source_root` (for example, with lazy trees), so
+/// frames in `source_aliases` count as well. Frames in any other store path, like the flake's inputs, never do.
+pub(crate) fn error_location(
+    stderr: &str,
+    source_root: &Path,
+    source_aliases: &[&Path],
+) -> Option<ErrorLocation> {
+    static AT: std::sync::LazyLock<Regex> = std::sync::LazyLock::new(|| {
+        Regex::new(r"\bat (/[^\s:]+):(\d+):(\d+)").expect("the trace location pattern is valid")
+    });
+
+    AT.captures_iter(stderr)
+        .filter_map(|captures| {
+            let path = Path::new(captures.get(1)?.as_str());
+            let file = std::iter::once(source_root)
+                .chain(source_aliases.iter().copied())
+                .find_map(|root| path.strip_prefix(root).ok())?
+                .to_path_buf();
+            if !source_root.join(&file).is_file() {
+                return None;
+            }
+            Some(ErrorLocation {
+                file,
+                line: captures.get(2)?.as_str().parse().ok()?,
+                col: captures.get(3)?.as_str().parse().ok()?,
+            })
+        })
+        .last()
+}
+
+/// The outcome of evaluating each [`EvaluationTarget`] of a flake.
+#[derive(Debug, Default)]
+pub(crate) struct EvaluationReport {
+    pub(crate) evaluated: Vec<EvaluationTarget>,
+    pub(crate) failures: Vec<EvaluationFailure>,
+    /// Human readable notes on what was left out of the evaluation, and why.
+    pub(crate) notes: Vec<String>,
+}
+
+impl EvaluationReport {
+    pub(crate) fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Output a Github Actions annotation for each failure.
+    ///
+    /// Failures whose trace leads into the flake's own source (in `source_root`, or one of `source_aliases`) are
+    /// annotated on that line.
+    pub(crate) fn github_actions_annotations(&self, source_root: &Path, source_aliases: &[&Path]) {
+        for failure in &self.failures {
+            let location = match error_location(&failure.stderr, source_root, source_aliases) {
+                Some(location) => format!(
+                    "file={},line={},col={},",
+                    github_actions::escape_property(&location.file.to_string_lossy()),
+                    location.line,
+                    location.col
+                ),
+                None => String::new(),
+            };
+            println!(
+                "::error {location}title={}::{}",
+                github_actions::escape_property(&format!(
+                    "Evaluation of {} failed",
+                    failure.target
+                )),
+                github_actions::escape_data(failure.summary()),
+            );
+        }
+    }
+}
+
+/// With `{:#}`, only the `error: ...` portion of each failure is included, rather than the command and all of its stderr.
+impl Display for EvaluationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Evaluated {} flake output(s), {} failed",
+            self.evaluated.len(),
+            self.failures.len()
+        )?;
+        for note in &self.notes {
+            writeln!(f, "Note: {note}")?;
+        }
+        for failure in &self.failures {
+            writeln!(f)?;
+            writeln!(
+                f,
+                "`{}` failed to evaluate{}",
+                failure.target,
+                match failure.status {
+                    Some(status) => format!(" with status {status}"),
+                    None => String::new(),
+                }
+            )?;
+            if f.alternate() {
+                writeln!(f, "{}", failure.summary())?;
+            } else {
+                writeln!(f, "command: `{}`", failure.command)?;
+                writeln!(f, "stderr: {}", failure.stderr.trim())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Determine the units of evaluation of the flake in `source_dir` which fall within `scope`.
+pub(crate) async fn evaluation_targets(
+    nix: &Nix,
+    source_dir: &Path,
+    scope: &EvaluationScope,
+    report: &mut EvaluationReport,
+) -> Result<Vec<EvaluationTarget>> {
+    let flake_ref = format!("path:{}", source_dir.display());
+
+    let mut command = nix.command();
+    command
+        .arg("eval")
+        .arg("--json")
+        .arg("--impure")
+        .arg("--no-write-lock-file")
+        .arg("--expr")
+        .arg(format!(
+            "({LIST_OUTPUTS_EXPR}) {} {}",
+            nix_string(&flake_ref),
+            nix_list(PER_SYSTEM_OUTPUTS)
+        ));
+    let output = nix
+        .output(NixPhase::Evaluation, &mut command)
+        .await
+        .wrap_err("Failed to execute `nix eval` to list the flake's outputs")?;
+
+    if !output.status.success() {
+        return Err(eyre!(
+            "Failed to list the outputs of {}:\n{}",
+            source_dir.display(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let layout: BTreeMap<String, Option<Vec<String>>> = serde_json::from_slice(&output.stdout)
+        .wrap_err("Parsing the list of flake outputs as JSON")?;
+
+    report.notes.extend(scope.notes());
+    if let Some(outputs) = &scope.outputs {
+        for output in outputs {
+            if !layout.contains_key(output) {
+                report.notes.push(format!(
+                    "`{output}` was requested, but the flake has no such output"
+                ));
+            }
+        }
+    }
+
+    let mut targets = Vec::new();
+    for (output, output_systems) in layout {
+        if !scope.includes_output(&output) {
+            continue;
+        }
+        if UNEVALUATED_OUTPUTS.contains(&output.as_str()) {
+            report.notes.push(format!(
+                "`{output}` was not evaluated, like `nix flake show`"
+            ));
+            continue;
+        }
+
+        match output_systems {
+            Some(output_systems) => {
+                for system in output_systems {
+                    if !scope.includes_system(&system) {
+                        continue;
+                    }
+                    targets.push(EvaluationTarget {
+                        output: output.clone(),
+                        system: Some(system),
+                    });
+                }
+            }
+            None => targets.push(EvaluationTarget {
+                output,
+                system: None,
+            }),
+        }
+    }
+
+    Ok(targets)
+}
+
+/// Evaluate each target, running at most `max_jobs` evaluations at a time.
+pub(crate) async fn evaluate(
+    nix: &Nix,
+    source_dir: &Path,
+    targets: Vec<EvaluationTarget>,
+    max_jobs: usize,
+    report: &mut EvaluationReport,
+) -> Result<()> {
+    let semaphore = Arc::new(Semaphore::new(max_jobs.max(1)));
+    let mut jobs = JoinSet::new();
+
+    for target in targets {
+        let semaphore = semaphore.clone();
+        let nix = nix.clone();
+        let source_dir = source_dir.to_path_buf();
+        jobs.spawn(
+            async move {
+                let _permit = semaphore.acquire_owned().await?;
+                evaluate_target(&nix, &source_dir, target).await
+            }
+            .in_current_span(),
+        );
+    }
+
+    while let Some(joined) = jobs.join_next().await {
+        let (target, failure) = joined.wrap_err("Evaluation task panicked")??;
+        report.evaluated.push(target);
+        if let Some(failure) = failure {
+            report.failures.push(failure);
+        }
+    }
+
+    report.evaluated.sort();
+    report.failures.sort_by(|a, b| a.target.cmp(&b.target));
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(nix, source_dir), fields(%target))]
+async fn evaluate_target(
+    nix: &Nix,
+    source_dir: &Path,
+    target: EvaluationTarget,
+) -> Result<(EvaluationTarget, Option<EvaluationFailure>)> {
+    // The same flake reference the outputs were listed from, so untracked files count in both
+    let installable = format!("path:{}#{}", source_dir.display(), target.attr_path());
+    let apply = if target.system.is_some() {
+        PER_SYSTEM_APPLY
+    } else {
+        OTHER_APPLY
+    };
+
+    tracing::debug!("Evaluating");
+    let mut command = nix.command();
+    command
+        .arg("eval")
+        .arg("--json")
+        .arg("--no-write-lock-file")
+        .arg(&installable)
+        .arg("--apply")
+        .arg(apply);
+    let output = nix
+        .output(NixPhase::Evaluation, &mut command)
+        .await
+        .wrap_err_with(|| eyre!("Failed to execute `nix eval {installable}`"))?;
+
+    if output.status.success() {
+        return Ok((target, None));
+    }
+
+    let failure = EvaluationFailure {
+        target: target.clone(),
+        command: crate::nix::describe(&command),
+        status: output.status.code(),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    };
+    Ok((target, Some(failure)))
+}
+
+/// The system Nix builds for by default, like `x86_64-linux`.
+pub(crate) async fn current_system(nix: &Nix) -> Result<String> {
+    let mut command = nix.command();
+    command
+        .arg("eval")
+        .arg("--impure")
+        .arg("--raw")
+        .arg("--expr")
+        .arg("builtins.currentSystem");
+    let output = nix
+        .output(NixPhase::Evaluation, &mut command)
+        .await
+        .wrap_err("Failed to execute `nix eval --impure --raw --expr builtins.currentSystem`")?;
+
+    if !output.status.success() {
+        return Err(eyre!(
+            "Failed to determine the current system:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Quote a string as a Nix string literal.
+fn nix_string(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace("${", "\\${")
+    )
+}
+
+fn nix_list(values: &[impl AsRef<str>]) -> String {
+    let items: Vec<String> = values.iter().map(|v| nix_string(v.as_ref())).collect();
+    format!("[ {} ]", items.join(" "))
+}
+
+fn nix_optional_list(values: Option<&[String]>) -> String {
+    match values {
+        Some(values) => nix_list(values),
+        None => "null".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{error_location, ErrorLocation};
+
+    #[test]
+    fn error_location_from_trace() {
+        let source_root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(source_root.path().join("nix")).unwrap();
+        std::fs::write(source_root.path().join("flake.nix"), "").unwrap();
+        std::fs::write(source_root.path().join("nix/package.nix"), "").unwrap();
+
+        let stderr = format!(
+            "\
+error:
+       … while evaluating the attribute 'packages.x86_64-linux.default'
+         at {root}/flake.nix:12:5:
+           11|   outputs = {{ self, nixpkgs }}: {{
+           12|     packages.x86_64-linux.default = import ./nix/package.nix {{ }};
+             |     ^
+       … while calling the 'derivationStrict' builtin
+         at /nix/store/0123456789abcdfghijklmnpqrsvwxyz-source/nix/package.nix:3:7:
+       … while evaluating derivation
+         at /nix/store/zyxwvsrqpnmlkjihgfdcba9876543210-source/pkgs/stdenv/generic/make-derivation.nix:336:7:
+       … while calling a function from an input's flake
+         at /nix/store/zyxwvsrqpnmlkjihgfdcba9876543210-source/flake.nix:20:9:
+       error: undefined variable 'hello'
+",
+            root = source_root.path().display()
+        );
+
+        // With lazy trees, the flake's own source may be at another store path
+        let lazy_source = Path::new("/nix/store/0123456789abcdfghijklmnpqrsvwxyz-source");
+        assert_eq!(
+            error_location(&stderr, source_root.path(), &[lazy_source]),
+            Some(ErrorLocation {
+                file: PathBuf::from("nix/package.nix"),
+                line: 3,
+                col: 7,
+            })
+        );
+        // Only the flake's own source, even though the input's `flake.nix` exists in it too
+        assert_eq!(
+            error_location(&stderr, source_root.path(), &[]),
+            Some(ErrorLocation {
+                file: PathBuf::from("flake.nix"),
+                line: 12,
+                col: 5,
+            })
+        );
+        assert_eq!(
+            error_location(
+                "error: undefined variable 'hello'",
+                source_root.path(),
+                &[lazy_source]
+            ),
+            None
+        );
+    }
+}
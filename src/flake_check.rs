@@ -0,0 +1,194 @@
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+
+use crate::error::Error;
+use crate::github_actions;
+use crate::nix::{Nix, NixPhase};
+
+/// A failed run of `nix flake check`.
+#[derive(Debug)]
+pub(crate) struct FlakeCheckFailure {
+    pub(crate) system: Option<String>,
+    pub(crate) command: String,
+    pub(crate) status: Option<i32>,
+    pub(crate) stderr: String,
+    /// The checks (or derivations) Nix reported as failing.
+    pub(crate) failing_checks: Vec<String>,
+}
+
+/// With `{:#}`, the stderr of `nix flake check` is left out.
+impl Display for FlakeCheckFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "`{}` failed{}",
+            self.command,
+            match self.status {
+                Some(status) => format!(" with status {status}"),
+                None => String::new(),
+            }
+        )?;
+        if !self.failing_checks.is_empty() {
+            writeln!(f, "failing checks:")?;
+            for check in &self.failing_checks {
+                writeln!(f, "  - {check}")?;
+            }
+        }
+        if f.alternate() {
+            return Ok(());
+        }
+        writeln!(f, "stderr: {}", self.stderr.trim())
+    }
+}
+
+/// Run `nix flake check` on the flake in `source_dir`.
+///
+/// If `systems` is set, the flake is checked once for each of them, otherwise it is checked for all systems.
+/// Unless `build` is set, checks are only evaluated (`--no-build`).
+pub(crate) async fn check(
+    nix: &Nix,
+    source_dir: &Path,
+    systems: Option<&[String]>,
+    build: bool,
+    workflow_commands: bool,
+) -> Result<()> {
+    let runs: Vec<Option<&str>> = match systems {
+        Some(systems) => systems.iter().map(|s| Some(s.as_str())).collect(),
+        None => vec![None],
+    };
+
+    let mut failures = Vec::new();
+    for system in runs {
+        let mut args = vec!["flake", "check", "--keep-going", "--no-write-lock-file"];
+        if !build {
+            args.push("--no-build");
+        }
+        match system {
+            Some(system) => args.extend(["--system", system]),
+            None => args.push("--all-systems"),
+        }
+
+        let command = format!("nix {} {}", args.join(" "), source_dir.display());
+        tracing::debug!(%command, "Checking flake");
+
+        let mut nix_command = nix.command();
+        nix_command.args(&args).arg(source_dir);
+        let output = nix
+            .output(NixPhase::FlakeCheck, &mut nix_command)
+            .await
+            .wrap_err_with(|| eyre!("Failed to execute `{command}`"))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+            failures.push(FlakeCheckFailure {
+                system: system.map(ToString::to_string),
+                command,
+                status: output.status.code(),
+                failing_checks: failing_checks(&stderr),
+                stderr,
+            });
+        }
+    }
+
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    if workflow_commands {
+        for failure in &failures {
+            let title = match &failure.system {
+                Some(system) => format!("Flake check failed on {system}"),
+                None => "Flake check failed".to_string(),
+            };
+            let message = if failure.failing_checks.is_empty() {
+                failure.stderr.trim().to_string()
+            } else {
+                format!("Failing checks: {}", failure.failing_checks.join(", "))
+            };
+            println!(
+                "::error title={}::{}",
+                github_actions::escape_property(&title),
+                github_actions::escape_data(&message)
+            );
+        }
+    }
+
+    let report: Vec<String> = failures.iter().map(ToString::to_string).collect();
+    if workflow_commands {
+        // The stderr is kept to the collapsed log group of this phase, so the final error stays short
+        tracing::error!("{}", report.join("\n"));
+        let brief: Vec<String> = failures.iter().map(|f| format!("{f:#}")).collect();
+        return Err(Error::FlakeCheckFailed(brief.join("\n")))?;
+    }
+    Err(Error::FlakeCheckFailed(report.join("\n")))?
+}
+
+/// Extract the names of failing checks from the stderr of `nix flake check`.
+///
+/// Evaluation failures name the check (`checks.x86_64-linux.foo`), build failures only name the derivation.
+fn failing_checks(stderr: &str) -> Vec<String> {
+    const MARKERS: &[&str] = &[
+        "while checking the derivation '",
+        "builder for '",
+        "Cannot build '",
+    ];
+
+    let mut checks = BTreeSet::new();
+    for line in stderr.lines() {
+        for marker in MARKERS {
+            let Some((_, rest)) = line.split_once(marker) else {
+                continue;
+            };
+            let Some((name, _)) = rest.split_once('\'') else {
+                continue;
+            };
+            checks.insert(derivation_name(name).to_string());
+        }
+    }
+
+    checks.into_iter().collect()
+}
+
+/// Turn `/nix/store/<hash>-foo-1.0.drv` into `foo-1.0`, leaving anything else untouched.
+fn derivation_name(name: &str) -> &str {
+    let Some(base) = name
+        .strip_prefix("/nix/store/")
+        .and_then(|n| n.strip_suffix(".drv"))
+    else {
+        return name;
+    };
+    base.split_once('-').map(|(_, name)| name).unwrap_or(base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::failing_checks;
+
+    #[test]
+    fn failing_checks_from_stderr() {
+        let stderr = "\
+evaluating flake...
+checking flake output 'checks'...
+error:
+       … while checking flake output 'checks'
+       … while checking the derivation 'checks.x86_64-linux.formatting'
+       error: attribute 'nixfmt' missing
+error: builder for '/nix/store/0123456789abcdfghijklmnpqrsvwxyz-clippy-0.1.0.drv' failed with exit code 101;
+       last 10 log lines:
+error: Cannot build '/nix/store/abcdfghijklmnpqrsvwxyz0123456789-tests-0.1.0.drv'.
+error: 3 dependencies of derivation '/nix/store/zyxwvsrqpnmlkjihgfdcba9876543210-all.drv' failed to build
+";
+
+        assert_eq!(
+            failing_checks(stderr),
+            vec![
+                "checks.x86_64-linux.formatting".to_string(),
+                "clippy-0.1.0".to_string(),
+                "tests-0.1.0".to_string(),
+            ]
+        );
+    }
+}
@@ -1,29 +1,51 @@
 use std::path::{Path, PathBuf};
 
 use color_eyre::eyre::{eyre, Result, WrapErr};
-use flake_schemas::{InspectOptions, InspectOutput};
+use flake_schemas::InspectOutput;
 
+use crate::cli::Telemetry;
+use crate::error::Error;
+use crate::evaluation::{self, EvaluationReport, EvaluationScope};
+use crate::flake_check;
 use crate::flakehub_client::Tarball;
+use crate::nix::{Nix, NixPhase};
+use crate::timings::Phase;
 
 const README_FILENAME_LOWERCASE: &str = "readme.md";
 
+// The same references `flake_schemas::inspect_with_options` uses, which doesn't let us filter the
+// inventory before it's evaluated, or run Nix the way it's configured. Everything else, like the
+// errors and the output, is shared with it.
+const INSPECT_INCLUDING_OUTPUTS: &str =
+    "https://flakehub.com/f/DeterminateSystems/inspect/*#contents.includingOutputPaths";
+const INSPECT_EXCLUDING_OUTPUTS: &str =
+    "https://flakehub.com/f/DeterminateSystems/inspect/*#contents.excludingOutputPaths";
+
 #[derive(Debug)]
 pub struct FlakeMetadata {
     pub(crate) source_dir: std::path::PathBuf,
     pub(crate) flake_locked_url: String,
     pub(crate) metadata_json: serde_json::Value,
-    my_flake_is_too_big: bool,
+    nix: Nix,
+    /// Whether to annotate failures with GitHub's workflow commands.
+    workflow_commands: bool,
 }
 
 impl FlakeMetadata {
-    pub async fn from_dir(directory: &Path, my_flake_is_too_big: bool) -> Result<Self> {
-        let output = tokio::process::Command::new("nix")
+    pub async fn from_dir(nix: &Nix, directory: &Path, telemetry: &Telemetry) -> Result<Self> {
+        let mut command = nix.command();
+        command
             .arg("flake")
             .arg("metadata")
             .arg("--json")
             .arg("--no-write-lock-file")
-            .arg(directory)
-            .output()
+            .arg(directory);
+        let output = telemetry
+            .timings
+            .phase(
+                Phase::Metadata,
+                nix.output(NixPhase::Metadata, &mut command),
+            )
             .await
             .wrap_err_with(|| {
                 eyre!(
@@ -51,13 +73,19 @@ impl FlakeMetadata {
             .pointer("/resolved/dir")
             .and_then(serde_json::Value::as_str);
 
-        let output = tokio::process::Command::new("nix")
+        let mut command = nix.command();
+        command
             .arg("flake")
             .arg("prefetch")
             .arg("--json")
             .arg("--no-write-lock-file")
-            .arg(directory)
-            .output()
+            .arg(directory);
+        let output = telemetry
+            .timings
+            .phase(
+                Phase::Prefetch,
+                nix.output(NixPhase::Prefetch, &mut command),
+            )
             .await
             .wrap_err_with(|| {
                 eyre!(
@@ -92,55 +120,60 @@ impl FlakeMetadata {
             source_dir: source,
             flake_locked_url: flake_locked_url.to_string(),
             metadata_json,
-            my_flake_is_too_big,
+            nix: nix.clone(),
+            workflow_commands: telemetry.workflow_commands,
         })
     }
 
-    /// check_evalutes checks that the flake evaluates
+    /// check_evalutes checks that each output of the flake within `scope` evaluates, separately for each system
     /// (note it is not necessary for the target to have a flake.lock)
-    pub async fn check_evaluates(&self) -> Result<()> {
-        let mut command = tokio::process::Command::new("nix");
-        command.arg("flake");
-        command.arg("show");
-
-        if !self.my_flake_is_too_big {
-            command.arg("--all-systems");
+    pub async fn check_evaluates(
+        &self,
+        scope: &EvaluationScope,
+        max_jobs: usize,
+    ) -> Result<EvaluationReport> {
+        let mut report = EvaluationReport::default();
+
+        let targets =
+            evaluation::evaluation_targets(&self.nix, &self.source_dir, scope, &mut report).await?;
+        evaluation::evaluate(&self.nix, &self.source_dir, targets, max_jobs, &mut report).await?;
+
+        if !report.is_success() {
+            if self.workflow_commands {
+                report.github_actions_annotations(self.source_root(), &self.source_aliases());
+                // The full stderr of each failure lands in the collapsed log group of this phase,
+                // so the final error stays short enough to read.
+                tracing::error!("{report}");
+                return Err(Error::EvaluationFailed(format!("{report:#}")))?;
+            }
+            return Err(Error::EvaluationFailed(report.to_string()))?;
         }
 
-        command.arg("--json");
-        command.arg("--no-write-lock-file");
-        command.arg(&self.source_dir);
+        tracing::debug!("{report}");
+        Ok(report)
+    }
 
-        let output = command.output().await.wrap_err_with(|| {
-            eyre!(
-                "Failed to execute `nix flake show --all-systems --json --no-write-lock-file {}`",
-                self.source_dir.display()
-            )
-        })?;
-
-        if !output.status.success() {
-            let command = format!(
-                "nix flake show --all-systems --json --no-write-lock-file {}",
-                self.source_dir.display(),
-            );
-            let msg = format!(
-                "\
-                    Failed to execute command `{command}`{maybe_status} \n\
-                    stdout: {stdout}\n\
-                    stderr: {stderr}\n\
-                    ",
-                stdout = String::from_utf8_lossy(&output.stdout),
-                stderr = String::from_utf8_lossy(&output.stderr),
-                maybe_status = if let Some(status) = output.status.code() {
-                    format!(" with status {status}")
-                } else {
-                    String::new()
-                }
-            );
-            return Err(eyre!(msg))?;
-        }
+    /// The root of the flake's source (the repository), which is `source_dir` unless the flake is in a subdirectory.
+    fn source_root(&self) -> &Path {
+        self.metadata_json
+            .pointer("/resolved/dir")
+            .and_then(serde_json::Value::as_str)
+            .and_then(|dir| {
+                let depth = Path::new(dir).components().count();
+                self.source_dir.ancestors().nth(depth)
+            })
+            .unwrap_or(&self.source_dir)
+    }
 
-        Ok(())
+    /// Where else Nix may say the flake's source is, like the path `nix flake metadata` reports with lazy trees.
+    fn source_aliases(&self) -> Vec<&Path> {
+        self.metadata_json
+            .get("path")
+            .and_then(serde_json::Value::as_str)
+            .map(Path::new)
+            .filter(|path| *path != self.source_root())
+            .into_iter()
+            .collect()
     }
 
     /// check_lock_if_exists is specifically to check locked flakes to make sure the flake.lock
@@ -149,13 +182,16 @@ impl FlakeMetadata {
     /// this does not ensure anything about the recentness of the locked revs.
     pub async fn check_lock_if_exists(&self) -> Result<()> {
         if self.source_dir.join("flake.lock").exists() {
-            let output = tokio::process::Command::new("nix")
+            let mut command = self.nix.command();
+            command
                 .arg("flake")
                 .arg("metadata")
                 .arg("--json")
                 .arg("--no-update-lock-file")
-                .arg(&self.source_dir)
-                .output()
+                .arg(&self.source_dir);
+            let output = self
+                .nix
+                .output(NixPhase::LockCheck, &mut command)
                 .await
                 .wrap_err_with(|| {
                     eyre!(
@@ -183,12 +219,25 @@ impl FlakeMetadata {
                         String::new()
                     }
                 );
-                return Err(eyre!(msg))?;
+                return Err(Error::LockDrift(msg))?;
             }
         }
         Ok(())
     }
 
+    /// check_flake runs `nix flake check` for the systems within `scope`, so a release can't be
+    /// published while its own checks are failing. Checks are only built if `build` is set.
+    pub async fn check_flake(&self, scope: &EvaluationScope, build: bool) -> Result<()> {
+        flake_check::check(
+            &self.nix,
+            &self.source_dir,
+            scope.systems.as_deref(),
+            build,
+            self.workflow_commands,
+        )
+        .await
+    }
+
     pub fn flake_tarball(&self) -> Result<Tarball> {
         let last_modified = if let Some(last_modified) = self.metadata_json.get("lastModified") {
             last_modified.as_u64().ok_or_else(|| {
@@ -267,15 +316,42 @@ impl FlakeMetadata {
         Ok(tarball)
     }
 
-    pub async fn outputs(&self, include_output_paths: bool) -> Result<InspectOutput> {
-        if self.my_flake_is_too_big {
-            return Ok(InspectOutput::new());
+    pub async fn outputs(
+        &self,
+        include_output_paths: bool,
+        scope: &EvaluationScope,
+    ) -> Result<InspectOutput> {
+        let inspect_flake_ref = if include_output_paths {
+            INSPECT_INCLUDING_OUTPUTS
+        } else {
+            INSPECT_EXCLUDING_OUTPUTS
+        };
+
+        let mut command = self.nix.command();
+        command.arg("eval");
+        command.arg("--json");
+        command.arg("--no-write-lock-file");
+        command.arg("--override-input");
+        command.arg("flake");
+        command.arg(&self.flake_locked_url);
+        command.arg(inspect_flake_ref);
+
+        if scope.is_restricted() {
+            command.arg("--apply");
+            command.arg(scope.inspect_filter_expr());
         }
 
-        let options = InspectOptions::new().with_output(include_output_paths);
+        let output = self.nix.output(NixPhase::Inspect, &mut command).await?;
 
-        flake_schemas::inspect_with_options(&self.flake_locked_url, &options)
-            .wrap_err_with(|| eyre!("Parsing flake outputs from {}", self.flake_locked_url))
+        let outputs = if output.status.success() {
+            serde_json::from_slice(&output.stdout).map_err(flake_schemas::Error::from)
+        } else {
+            Err(flake_schemas::Error::ExitFailure {
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).into(),
+            })
+        };
+        outputs.wrap_err_with(|| eyre!("Parsing flake outputs from {}", self.flake_locked_url))
     }
 
     #[tracing::instrument(skip_all, fields(readme_dir))]
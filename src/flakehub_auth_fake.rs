@@ -1,25 +1,147 @@
 use color_eyre::eyre::{Context, Result};
 
-use crate::github::graphql::GithubGraphqlDataResult;
+use crate::{
+    cli::FlakeHubPushCli,
+    dev_jwt_issuer::DevJwtIssuer,
+    error::Error,
+    git_context::GitContext,
+    github::graphql::GithubGraphqlDataResult,
+    provider::{Authenticator, Provider, Repository},
+    revision_info::RevisionInfo,
+};
+
+/// Running locally for development, emulating GitHub Actions with tokens from a JWT mock server (or a built-in
+/// issuer).
+pub(crate) struct LocalGitHub;
+
+impl LocalGitHub {
+    pub(crate) const NAME: &'static str = "local";
+}
+
+#[async_trait::async_trait]
+impl Provider for LocalGitHub {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn detect(&self, cli: &FlakeHubPushCli) -> Vec<String> {
+        let mut evidence = vec![];
+        if cli.jwt_issuer_uri.is_some() {
+            evidence.push("`--jwt-issuer-uri`".to_string());
+        }
+        if cli.dev_jwt_issuer {
+            evidence.push("`--dev-jwt-issuer`".to_string());
+        }
+        evidence
+    }
+
+    async fn prepare(
+        &self,
+        cli: &FlakeHubPushCli,
+        client: &reqwest::Client,
+        repository: &Repository,
+        local_rev_info: RevisionInfo,
+    ) -> Result<(GitContext, Box<dyn Authenticator>)> {
+        let issuer = match (&cli.jwt_issuer_uri, cli.dev_jwt_issuer) {
+            (Some(jwt_issuer_uri), _) => Issuer::External(jwt_issuer_uri.clone()),
+            (None, true) => {
+                let port = cli
+                    .dev_jwt_issuer_port
+                    .0
+                    .map(u16::try_from)
+                    .transpose()
+                    .wrap_err("`--dev-jwt-issuer-port` must be a valid port")?;
+                Issuer::BuiltIn(Box::new(DevJwtIssuer::start(port).await?))
+            }
+            (None, false) => {
+                return Err(Error::MissingConfiguration(
+                    "`--jwt-issuer-uri` or `--dev-jwt-issuer` is required to push from the `local` environment".to_string(),
+                ))?;
+            }
+        };
+
+        let github_token = cli
+            .github_token
+            .0
+            .clone()
+            .expect("failed to get github token when running locally");
+
+        let github_graphql_data_result = crate::github::query_repository(
+            cli,
+            client,
+            &github_token,
+            repository,
+            cli.rev.0.as_ref().unwrap_or(&local_rev_info.revision),
+        )
+        .await?;
+
+        let git_ctx = GitContext::from_cli_and_github(cli, &github_graphql_data_result)?;
+
+        Ok((
+            git_ctx,
+            Box::new(FakeToken {
+                issuer,
+                project_owner: repository.owner.clone(),
+                repository: repository.full_name.clone(),
+                github_graphql_data_result,
+            }),
+        ))
+    }
+}
+
+/// Where dev-signed tokens come from.
+enum Issuer {
+    /// A JWT mock server, like https://github.com/spectare/fakeidp.
+    External(String),
+    /// The in-process issuer started by `--dev-jwt-issuer`.
+    BuiltIn(Box<DevJwtIssuer>),
+}
+
+/// A dev-signed token, minted by a JWT mock server or the in-process development issuer.
+struct FakeToken {
+    issuer: Issuer,
+    project_owner: String,
+    repository: String,
+    github_graphql_data_result: GithubGraphqlDataResult,
+}
+
+#[async_trait::async_trait]
+impl Authenticator for FakeToken {
+    async fn bearer_token(&self) -> Result<String> {
+        match &self.issuer {
+            Issuer::External(jwt_issuer_uri) => {
+                get_fake_bearer_token(
+                    jwt_issuer_uri,
+                    &self.project_owner,
+                    &self.repository,
+                    &self.github_graphql_data_result,
+                )
+                .await
+            }
+            Issuer::BuiltIn(issuer) => {
+                tracing::warn!("running outside github/gitlab - minting a dev-signed JWT");
+                issuer.mint(fake_claims(
+                    &self.project_owner,
+                    &self.repository,
+                    &self.github_graphql_data_result,
+                ))
+            }
+        }
+    }
+}
 
 pub async fn get_fake_bearer_token(
     jwt_issuer_uri: &str,
     project_owner: &str,
     repository: &str,
-    github_graphql_data_result: GithubGraphqlDataResult,
+    github_graphql_data_result: &GithubGraphqlDataResult,
 ) -> Result<String> {
     tracing::warn!("running outside github/gitlab - minting a dev-signed JWT");
 
     let client = reqwest::Client::new();
 
-    let mut claims = github_actions_oidc_claims::Claims::make_dummy();
-    claims.aud = "flakehub-localhost".to_string();
+    let mut claims = fake_claims(project_owner, repository, github_graphql_data_result);
     claims.iss = jwt_issuer_uri.to_string();
-    claims.repository = repository.to_string();
-    claims.repository_owner = project_owner.to_string();
-
-    claims.repository_id = github_graphql_data_result.project_id.to_string();
-    claims.repository_owner_id = github_graphql_data_result.owner_id.to_string();
 
     let issuer_url = url::Url::parse(jwt_issuer_uri)?;
     let token_gen_endpoint = issuer_url.join("/token")?;
@@ -38,3 +160,20 @@ pub async fn get_fake_bearer_token(
         .wrap_err("Getting token from JWT issuer's response")?;
     Ok(token)
 }
+
+/// GitHub Actions-like claims for `repository`, for a local FlakeHub.
+fn fake_claims(
+    project_owner: &str,
+    repository: &str,
+    github_graphql_data_result: &GithubGraphqlDataResult,
+) -> github_actions_oidc_claims::Claims {
+    let mut claims = github_actions_oidc_claims::Claims::make_dummy();
+    claims.aud = "flakehub-localhost".to_string();
+    claims.repository = repository.to_string();
+    claims.repository_owner = project_owner.to_string();
+
+    claims.repository_id = github_graphql_data_result.project_id.to_string();
+    claims.repository_owner_id = github_graphql_data_result.owner_id.to_string();
+
+    claims
+}
@@ -1,15 +1,26 @@
-use color_eyre::eyre::{eyre, Context, Result};
+use std::time::Duration;
+
+use color_eyre::eyre::{Context, Result};
 use http::StatusCode;
 use reqwest::header::HeaderMap;
 use reqwest::Response;
 use uuid::Uuid;
 
+use crate::cli::Telemetry;
+use crate::error::Error;
+use crate::provider::Authenticator;
 use crate::release_metadata::ReleaseMetadata;
+use crate::token_claims::TokenClaims;
+
+/// Re-acquire the token when it expires in less than this, so it can't expire in the middle of a request.
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
 
 pub struct FlakeHubClient {
     host: url::Url,
-    bearer_token: String,
+    authenticator: Box<dyn Authenticator>,
+    bearer_token: tokio::sync::Mutex<Option<String>>,
     client: reqwest::Client,
+    telemetry: Telemetry,
 }
 
 pub struct Tarball {
@@ -35,32 +46,85 @@ pub fn flakehub_headers() -> HeaderMap {
 }
 
 impl FlakeHubClient {
-    pub fn new(host: url::Url, bearer_token: String) -> Result<Self> {
+    pub(crate) fn new(
+        host: url::Url,
+        authenticator: Box<dyn Authenticator>,
+        telemetry: &Telemetry,
+    ) -> Result<Self> {
         let builder = reqwest::ClientBuilder::new().user_agent("flakehub-push");
 
         let client = builder.build()?;
 
         let client = Self {
             client,
-            bearer_token,
+            authenticator,
+            bearer_token: tokio::sync::Mutex::new(None),
             host,
+            telemetry: telemetry.clone(),
         };
 
         Ok(client)
     }
 
+    /// The token to authenticate with, which is acquired on first use and re-acquired when it's about to expire.
+    ///
+    /// Tokens without an `exp` claim are assumed not to expire.
+    pub async fn bearer_token(&self) -> Result<String> {
+        let mut bearer_token = self.bearer_token.lock().await;
+
+        if let Some(token) = bearer_token.as_ref() {
+            let expires_in = TokenClaims::decode_unverified(token)
+                .ok()
+                .and_then(|claims| claims.expires_in());
+            match expires_in {
+                Some(expires_in) if expires_in < TOKEN_REFRESH_MARGIN => {
+                    tracing::info!(
+                        expires_in_secs = expires_in.as_secs(),
+                        "The auth token is about to expire, acquiring a new one"
+                    );
+                }
+                _ => return Ok(token.clone()),
+            }
+        }
+
+        let token = self.authenticator.bearer_token().await?;
+        self.telemetry.add_mask(&token);
+        *bearer_token = Some(token.clone());
+
+        Ok(token)
+    }
+
+    /// The token most recently sent, if any, which may have been re-acquired since the first request.
+    pub async fn current_token(&self) -> Option<String> {
+        self.bearer_token.lock().await.clone()
+    }
+
     pub async fn token_status(&self) -> Result<Response> {
         let status_url = self.host.join("token/")?.join("status")?;
 
         self.client
             .get(status_url)
-            .bearer_auth(&self.bearer_token)
+            .bearer_auth(self.bearer_token().await?)
             .headers(flakehub_headers())
             .send()
             .await
             .wrap_err("Checking token status")
     }
 
+    /// The response to [`Self::token_status`] if FlakeHub accepted the token, or why it didn't.
+    pub(crate) async fn check_token_status(response: Response) -> Result<Response, Error> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let message = crate::response_text(response).await;
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            Err(Error::Unauthorized(message))
+        } else {
+            Err(Error::TokenStatus(format!("status {status}: {message}")))
+        }
+    }
+
     pub async fn release_stage(
         &self,
         upload_name: &str,
@@ -81,7 +145,7 @@ impl FlakeHubClient {
 
         self.client
             .post(release_metadata_post_url)
-            .bearer_auth(&self.bearer_token)
+            .bearer_auth(self.bearer_token().await?)
             .headers(flakehub_headers())
             .json(&release_metadata)
             .send()
@@ -98,11 +162,11 @@ impl FlakeHubClient {
         let publish_response = self
             .client
             .post(publish_post_url)
-            .bearer_auth(&self.bearer_token)
+            .bearer_auth(self.bearer_token().await?)
             .headers(flakehub_headers())
             .send()
             .await
-            .wrap_err("Publishing release")?;
+            .wrap_err(Error::Publish("Sending publish POST".to_string()))?;
 
         let publish_response_status = publish_response.status();
         tracing::trace!(
@@ -111,15 +175,87 @@ impl FlakeHubClient {
         );
 
         if publish_response_status != StatusCode::OK {
-            return Err(eyre!(
+            return Err(Error::Publish(format!(
                 "\
                     Status {publish_response_status} from publish POST\n\
                     {}\
                 ",
                 String::from_utf8_lossy(&publish_response.bytes().await.unwrap())
-            ));
+            )))?;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+
+    use super::{FlakeHubClient, TOKEN_REFRESH_MARGIN};
+    use crate::cli::Telemetry;
+    use crate::provider::Authenticator;
+
+    /// Mints tokens which expire `expires_in` from now, counting how many it minted.
+    struct Counting {
+        expires_in: Duration,
+        minted: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl Authenticator for Counting {
+        async fn bearer_token(&self) -> color_eyre::Result<String> {
+            let minted = self.minted.fetch_add(1, Ordering::SeqCst);
+            let exp = (SystemTime::now().duration_since(UNIX_EPOCH)? + self.expires_in).as_secs();
+            let payload = URL_SAFE_NO_PAD.encode(format!(r#"{{"exp":{exp},"jti":"{minted}"}}"#));
+            Ok(format!("e30.{payload}.c2ln"))
+        }
+    }
+
+    async fn tokens_minted(expires_in: Duration) -> usize {
+        let minted = Arc::new(AtomicUsize::new(0));
+        let client = FlakeHubClient::new(
+            url::Url::parse("https://api.flakehub.com").unwrap(),
+            Box::new(Counting {
+                expires_in,
+                minted: minted.clone(),
+            }),
+            &Telemetry::default(),
+        )
+        .unwrap();
+
+        client.bearer_token().await.unwrap();
+        let sent = client.bearer_token().await.unwrap();
+        assert_eq!(client.current_token().await, Some(sent));
+        minted.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn reacquires_tokens_about_to_expire() {
+        assert_eq!(tokens_minted(TOKEN_REFRESH_MARGIN / 2).await, 2);
+        assert_eq!(tokens_minted(TOKEN_REFRESH_MARGIN * 10).await, 1);
+    }
+
+    async fn token_status_exit_code(status: u16, body: &str) -> u8 {
+        let response = http::Response::builder()
+            .status(status)
+            .body(body.to_string())
+            .unwrap();
+        let error = FlakeHubClient::check_token_status(response.into())
+            .await
+            .unwrap_err();
+        let report = color_eyre::eyre::Report::new(error).wrap_err("Checking the token");
+        crate::error::find(&report).unwrap().exit_code()
+    }
+
+    #[tokio::test]
+    async fn rejected_tokens_exit_as_unauthorized() {
+        assert_eq!(token_status_exit_code(401, "token expired").await, 10);
+        assert_eq!(token_status_exit_code(403, "not allowed").await, 10);
+        assert_eq!(token_status_exit_code(502, "bad gateway").await, 13);
+    }
+}
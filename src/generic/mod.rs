@@ -0,0 +1,118 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use color_eyre::eyre::{eyre, WrapErr};
+
+use crate::{
+    cli::FlakeHubPushCli,
+    git_context::GitContext,
+    provider::{Authenticator, Provider, Repository},
+    revision_info::RevisionInfo,
+};
+
+/// Any other CI system (Semaphore, ...), which provides an OIDC token through the environment, a file or a command.
+pub(crate) struct Generic;
+
+#[async_trait::async_trait]
+impl Provider for Generic {
+    fn name(&self) -> &'static str {
+        "generic"
+    }
+
+    fn detect(&self, cli: &FlakeHubPushCli) -> Vec<String> {
+        let mut evidence = crate::provider::set_variables(&["FLAKEHUB_PUSH_OIDC_TOKEN"]);
+        if cli.oidc_token_file.0.is_some() {
+            evidence.push("`--oidc-token-file`".to_string());
+        }
+        if cli.oidc_token_command.0.is_some() {
+            evidence.push("`--oidc-token-command`".to_string());
+        }
+        evidence
+    }
+
+    async fn prepare(
+        &self,
+        cli: &FlakeHubPushCli,
+        _client: &reqwest::Client,
+        _repository: &Repository,
+        local_rev_info: RevisionInfo,
+    ) -> color_eyre::Result<(GitContext, Box<dyn Authenticator>)> {
+        let git_ctx = GitContext::from_cli(cli, local_rev_info).await?;
+
+        Ok((git_ctx, Box::new(TokenSource::from_cli(cli))))
+    }
+}
+
+/// Where a generic CI system's OIDC token comes from.
+#[derive(Debug, Clone)]
+pub(crate) enum TokenSource {
+    /// The `FLAKEHUB_PUSH_OIDC_TOKEN` environment variable.
+    Env,
+    /// A file holding the token, like a Kubernetes projected service account token. It's re-read on each use,
+    /// since the file is rotated in place.
+    File(PathBuf),
+    /// A shell command which prints the token, like `vault read -field=token ...`.
+    Command(String),
+}
+
+impl TokenSource {
+    pub(crate) fn from_cli(cli: &FlakeHubPushCli) -> Self {
+        if let Some(path) = &cli.oidc_token_file.0 {
+            Self::File(path.clone())
+        } else if let Some(command) = &cli.oidc_token_command.0 {
+            Self::Command(command.clone())
+        } else {
+            Self::Env
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticator for TokenSource {
+    async fn bearer_token(&self) -> color_eyre::Result<String> {
+        get_bearer_token(self)
+            .await
+            .wrap_err("Getting upload bearer token")
+    }
+}
+
+#[tracing::instrument(skip_all, fields(source = ?source))]
+pub(crate) async fn get_bearer_token(source: &TokenSource) -> color_eyre::Result<String> {
+    let token = match source {
+        TokenSource::Env => std::env::var("FLAKEHUB_PUSH_OIDC_TOKEN")
+            .wrap_err("missing FLAKEHUB_PUSH_OIDC_TOKEN environment variable")?,
+        TokenSource::File(path) => tokio::fs::read_to_string(path)
+            .await
+            .wrap_err_with(|| eyre!("Reading the OIDC token from {}", path.display()))?,
+        TokenSource::Command(command) => {
+            let output = tokio::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdin(Stdio::null())
+                .stderr(Stdio::piped())
+                .stdout(Stdio::piped())
+                .output()
+                .await
+                .wrap_err_with(|| eyre!("Running the OIDC token command `{command}`"))?;
+
+            if !output.status.success() {
+                return Err(eyre!(
+                    "The OIDC token command `{command}` failed with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+
+            String::from_utf8(output.stdout).wrap_err_with(|| {
+                eyre!("The OIDC token command `{command}` printed a token which isn't UTF-8")
+            })?
+        }
+    };
+
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(eyre!("The OIDC token from {source:?} is empty"));
+    }
+
+    Ok(token.to_string())
+}
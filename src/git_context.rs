@@ -2,7 +2,8 @@ use color_eyre::eyre::{Context, Result};
 use spdx::Expression;
 
 use crate::{
-    cli::FlakeHubPushCli, github::graphql::GithubGraphqlDataResult, revision_info::RevisionInfo,
+    cli::FlakeHubPushCli, gitea::GiteaRepoData, github::graphql::GithubGraphqlDataResult,
+    revision_info::RevisionInfo,
 };
 
 pub struct GitContext {
@@ -57,6 +58,38 @@ impl GitContext {
         Ok(ctx)
     }
 
+    pub fn from_cli_and_gitea(
+        cli: &FlakeHubPushCli,
+        gitea_repo_data: &GiteaRepoData,
+        local_revision_info: RevisionInfo,
+    ) -> Result<Self> {
+        let spdx_expression = match (&cli.spdx_expression.0, &gitea_repo_data.spdx_identifier) {
+            (Some(spdx_expression), _) => Some(spdx_expression.clone()),
+            (None, Some(spdx_string)) => {
+                tracing::debug!("Recieved SPDX identifier `{}` from Gitea API", spdx_string);
+                let parsed = spdx::Expression::parse(spdx_string)
+                    .wrap_err("Invalid SPDX license identifier reported from the Gitea API, pass `--spdx-expression` instead")?;
+                Some(parsed)
+            }
+            (None, None) => None,
+        };
+
+        let rev = cli.rev.0.as_ref().unwrap_or(&local_revision_info.revision);
+
+        let ctx = GitContext {
+            spdx_expression,
+            repo_topics: gitea_repo_data.topics.clone(),
+            revision_info: RevisionInfo {
+                // The local checkout may be shallow, so prefer the server's count
+                commit_count: gitea_repo_data
+                    .rev_count
+                    .or(local_revision_info.commit_count),
+                revision: rev.to_string(),
+            },
+        };
+        Ok(ctx)
+    }
+
     pub async fn from_cli_and_gitlab(
         cli: &FlakeHubPushCli,
         local_revision_info: RevisionInfo,
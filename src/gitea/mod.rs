@@ -0,0 +1,193 @@
+use color_eyre::eyre::{eyre, WrapErr};
+use serde::Deserialize;
+
+use crate::{
+    cli::FlakeHubPushCli,
+    error::Error,
+    generic::TokenSource,
+    git_context::GitContext,
+    provider::{Authenticator, Provider, Repository},
+    revision_info::RevisionInfo,
+};
+
+/// What flakehub-push needs to know about a repository, from the Gitea (or Forgejo) REST API.
+#[derive(Debug)]
+pub(crate) struct GiteaRepoData {
+    pub(crate) topics: Vec<String>,
+    pub(crate) spdx_identifier: Option<String>,
+    pub(crate) rev_count: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct TopicsResponse {
+    topics: Vec<String>,
+}
+
+impl GiteaRepoData {
+    #[tracing::instrument(skip(reqwest_client, token))]
+    pub(crate) async fn get(
+        reqwest_client: &reqwest::Client,
+        api_url: &url::Url,
+        token: Option<&str>,
+        project_owner: &str,
+        project_name: &str,
+        revision: &str,
+    ) -> color_eyre::Result<Self> {
+        let get = |path: String| {
+            let url = api_url.join(&path);
+            async move {
+                let url = url.wrap_err("Building a Gitea API URL")?;
+                tracing::debug!(%url, "Sending Gitea API request");
+                let mut request = reqwest_client.get(url.clone());
+                if let Some(token) = token {
+                    request =
+                        request.header(reqwest::header::AUTHORIZATION, format!("token {token}"));
+                }
+                let response = request
+                    .send()
+                    .await
+                    .wrap_err_with(|| eyre!("Sending request to {url}"))?;
+                response
+                    .error_for_status()
+                    .wrap_err_with(|| eyre!("Requesting {url}"))
+            }
+        };
+
+        let repo = format!("repos/{project_owner}/{project_name}");
+
+        let topics: TopicsResponse = get(format!("{repo}/topics"))
+            .await?
+            .json()
+            .await
+            .wrap_err("Parsing the repository's topics")?;
+
+        // Only newer versions of Gitea detect licenses, so don't fail if it can't.
+        let spdx_identifier = match get(format!("{repo}/licenses")).await {
+            Ok(response) => {
+                let licenses: Vec<String> = response
+                    .json()
+                    .await
+                    .wrap_err("Parsing the repository's licenses")?;
+                match licenses.as_slice() {
+                    [license] => Some(license.clone()),
+                    _ => None,
+                }
+            }
+            Err(e) => {
+                tracing::debug!("Could not get the repository's license: {e:#}");
+                None
+            }
+        };
+
+        let commits = get(format!(
+            "{repo}/commits?sha={revision}&limit=1&stat=false&verification=false&files=false"
+        ))
+        .await?;
+        let rev_count = commits
+            .headers()
+            .get("x-total-count")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+
+        Ok(Self {
+            topics: topics.topics,
+            spdx_identifier,
+            rev_count,
+        })
+    }
+}
+
+/// Gitea and Forgejo Actions.
+pub(crate) struct Gitea;
+
+#[async_trait::async_trait]
+impl Provider for Gitea {
+    fn name(&self) -> &'static str {
+        "gitea"
+    }
+
+    fn detect(&self, _cli: &FlakeHubPushCli) -> Vec<String> {
+        crate::provider::set_variables(&["GITEA_ACTIONS", "FORGEJO_ACTIONS"])
+    }
+
+    fn backfill(&self, cli: &mut FlakeHubPushCli) {
+        // https://docs.gitea.com/usage/actions/comparison#context-availability
+        // Gitea and Forgejo Actions mimic GitHub's environment variables
+        let github_graphql_url = cli.github_graphql_url.0.clone();
+        crate::github::GitHub.backfill(cli);
+        // ...but there's no GraphQL API
+        cli.github_graphql_url.0 = github_graphql_url;
+
+        if cli.gitea_api_url.is_none() {
+            let api_url = std::env::var("GITHUB_API_URL").ok().or_else(|| {
+                std::env::var("GITHUB_SERVER_URL")
+                    .ok()
+                    .map(|server| format!("{}/api/v1", server.trim_end_matches('/')))
+            });
+            if let Some(env_val) = api_url {
+                // Without a trailing slash, joining paths onto it would replace `v1`
+                match url::Url::parse(&format!("{}/", env_val.trim_end_matches('/'))) {
+                    Ok(api_url) => {
+                        tracing::debug!(gitea_api_url = %api_url, "Set via `$GITHUB_API_URL` or `$GITHUB_SERVER_URL`");
+                        cli.gitea_api_url = Some(api_url);
+                    }
+                    Err(e) => tracing::warn!("Ignoring invalid Gitea API URL `{env_val}`: {e}"),
+                }
+            }
+        }
+    }
+
+    async fn prepare(
+        &self,
+        cli: &FlakeHubPushCli,
+        client: &reqwest::Client,
+        repository: &Repository,
+        local_rev_info: RevisionInfo,
+    ) -> color_eyre::Result<(GitContext, Box<dyn Authenticator>)> {
+        let Some(gitea_api_url) = cli.gitea_api_url.as_ref() else {
+            return Err(Error::MissingConfiguration("`--gitea-api-url` was not specified and could not be populated from the GITHUB_API_URL or GITHUB_SERVER_URL environment variables".to_string()))?;
+        };
+
+        let gitea_repo_data = GiteaRepoData::get(
+            client,
+            gitea_api_url,
+            cli.github_token.0.as_deref(),
+            &repository.owner,
+            &repository.name,
+            cli.rev.0.as_ref().unwrap_or(&local_rev_info.revision),
+        )
+        .await
+        .wrap_err(Error::GiteaApi(
+            "Querying the repository from the Gitea API".to_string(),
+        ))?;
+
+        let git_ctx = GitContext::from_cli_and_gitea(cli, &gitea_repo_data, local_rev_info)?;
+
+        Ok((
+            git_ctx,
+            Box::new(ActionsIdToken {
+                host: cli.host.clone(),
+                fallback: TokenSource::from_cli(cli),
+            }),
+        ))
+    }
+}
+
+/// Forgejo supports the same protocol for OIDC tokens as GitHub does, otherwise fall back to the generic sources.
+struct ActionsIdToken {
+    host: url::Url,
+    fallback: TokenSource,
+}
+
+#[async_trait::async_trait]
+impl Authenticator for ActionsIdToken {
+    async fn bearer_token(&self) -> color_eyre::Result<String> {
+        if std::env::var("ACTIONS_ID_TOKEN_REQUEST_TOKEN").is_ok() {
+            crate::github::get_actions_id_bearer_token(&self.host)
+                .await
+                .wrap_err("Getting upload bearer token from Forgejo")
+        } else {
+            self.fallback.bearer_token().await
+        }
+    }
+}
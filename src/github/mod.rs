@@ -1,9 +1,18 @@
 pub(crate) mod graphql;
 
+use std::path::PathBuf;
+
 use color_eyre::eyre::{eyre, WrapErr};
 use serde::{Deserialize, Serialize};
 
-use crate::build_http_client;
+use crate::{
+    build_http_client,
+    cli::FlakeHubPushCli,
+    error::Error,
+    git_context::GitContext,
+    provider::{Authenticator, Provider, Repository},
+    revision_info::RevisionInfo,
+};
 
 const GITHUB_ACTOR_TYPE_USER: &str = "User";
 const GITHUB_ACTOR_TYPE_ORGANIZATION: &str = "Organization";
@@ -101,3 +110,131 @@ jobs:
 
     Ok(response_bearer_token.to_string())
 }
+
+/// GitHub Actions.
+pub(crate) struct GitHub;
+
+#[async_trait::async_trait]
+impl Provider for GitHub {
+    fn name(&self) -> &'static str {
+        "github"
+    }
+
+    fn detect(&self, _cli: &FlakeHubPushCli) -> Vec<String> {
+        crate::provider::set_variables(&["GITHUB_ACTION"])
+    }
+
+    fn backfill(&self, cli: &mut FlakeHubPushCli) {
+        // https://docs.github.com/en/actions/learn-github-actions/variables
+
+        if cli.git_root.0.is_none() {
+            let env_key = "GITHUB_WORKSPACE";
+            if let Ok(env_val) = std::env::var(env_key) {
+                tracing::debug!(git_root = %env_val, "Set via `${env_key}`");
+                cli.git_root.0 = Some(PathBuf::from(env_val));
+            }
+        }
+
+        if cli.repository.0.is_none() {
+            let env_key = "GITHUB_REPOSITORY";
+            if let Ok(env_val) = std::env::var(env_key) {
+                tracing::debug!(repository = %env_val, "Set via `${env_key}`");
+                cli.repository.0 = Some(env_val);
+            }
+        }
+
+        if cli.tag.0.is_none() {
+            let env_key = "GITHUB_REF_NAME";
+            if let Ok(env_val) = std::env::var(env_key) {
+                tracing::debug!(repository = %env_val, "Set via `${env_key}`");
+                cli.tag.0 = Some(env_val);
+            }
+        }
+
+        if cli.github_graphql_url.0.is_none() {
+            let env_key = "GITHUB_GRAPHQL_URL";
+            if let Ok(env_val) = std::env::var(env_key) {
+                tracing::debug!(repository = %env_val, "Set via `${env_key}`");
+                cli.github_graphql_url.0 = Some(env_val);
+            }
+        }
+    }
+
+    async fn prepare(
+        &self,
+        cli: &FlakeHubPushCli,
+        client: &reqwest::Client,
+        repository: &Repository,
+        local_rev_info: RevisionInfo,
+    ) -> color_eyre::Result<(GitContext, Box<dyn Authenticator>)> {
+        let github_token = cli
+            .github_token
+            .0
+            .clone()
+            .expect("failed to get github token when running in GitHub Actions");
+
+        let github_graphql_data_result = query_repository(
+            cli,
+            client,
+            &github_token,
+            repository,
+            cli.rev.0.as_ref().unwrap_or(&local_rev_info.revision),
+        )
+        .await?;
+
+        let git_ctx = GitContext::from_cli_and_github(cli, &github_graphql_data_result)?;
+
+        Ok((
+            git_ctx,
+            Box::new(ActionsIdToken {
+                host: cli.host.clone(),
+            }),
+        ))
+    }
+
+    fn github_workflow_commands(&self) -> bool {
+        true
+    }
+}
+
+/// The job's OIDC token from GitHub Actions.
+pub(crate) struct ActionsIdToken {
+    pub(crate) host: url::Url,
+}
+
+#[async_trait::async_trait]
+impl Authenticator for ActionsIdToken {
+    async fn bearer_token(&self) -> color_eyre::Result<String> {
+        get_actions_id_bearer_token(&self.host)
+            .await
+            .wrap_err("Getting upload bearer token from GitHub")
+    }
+}
+
+/// Query the repository from GitHub's GraphQL API.
+pub(crate) async fn query_repository(
+    cli: &FlakeHubPushCli,
+    client: &reqwest::Client,
+    github_token: &str,
+    repository: &Repository,
+    revision: &str,
+) -> color_eyre::Result<graphql::GithubGraphqlDataResult> {
+    let Some(github_graphql_url) = cli.github_graphql_url.0.as_ref() else {
+        return Err(Error::MissingConfiguration("`--github-graphql-url` was not specified and could not be populated from the GITHUB_GRAPHQL_URL environment variable".to_string()))?;
+    };
+
+    let github_graphql_data_result = graphql::GithubGraphqlDataQuery::get(
+        client,
+        github_graphql_url,
+        github_token,
+        &repository.owner,
+        &repository.name,
+        revision,
+    )
+    .await
+    .wrap_err(Error::GitHubApi(
+        "Querying the repository from GitHub's GraphQL API".to_string(),
+    ))?;
+
+    Ok(github_graphql_data_result)
+}
@@ -1,10 +1,20 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+
+use flake_schemas::InventoryItem;
 use tokio::io::AsyncWriteExt;
 
+use crate::evaluation::PER_SYSTEM_OUTPUTS;
+use crate::release_metadata::ReleaseMetadata;
+
 #[derive(Debug, thiserror::Error)]
 pub(crate) enum Error {
     #[error("The `GITHUB_OUTPUT` environment variable is unset.")]
     GithubOutputUnset,
 
+    #[error("The `GITHUB_STEP_SUMMARY` environment variable is unset.")]
+    GithubStepSummaryUnset,
+
     #[error("Failure opening {0:?}: {1}")]
     OpenFile(std::ffi::OsString, std::io::Error),
 
@@ -35,6 +45,255 @@ pub(crate) async fn set_output<'a>(name: &'a str, value: &'a str) -> Result<(),
     Ok(())
 }
 
+/// Hide `secret` from the job's logs in GitHub Actions, in case it's ever printed.
+pub(crate) fn add_mask(secret: &str) {
+    // Each line of a multi-line secret has to be masked separately
+    for line in secret.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        println!("::add-mask::{}", escape_data(line));
+    }
+}
+
+/// A collapsible group of log lines, which ends when this is dropped.
+pub(crate) struct LogGroup {
+    active: bool,
+}
+
+/// Start a collapsible group of log lines titled `title`, if `active`.
+///
+/// GitHub doesn't support nesting groups, so avoid starting one while another is open.
+pub(crate) fn group(title: &str, active: bool) -> LogGroup {
+    if active {
+        println!("::group::{}", escape_data(title));
+    }
+    LogGroup { active }
+}
+
+impl Drop for LogGroup {
+    fn drop(&mut self) {
+        if self.active {
+            println!("::endgroup::");
+        }
+    }
+}
+
+/// Append Markdown to the job summary shown on the workflow run's page.
+pub(crate) async fn append_step_summary(markdown: &str) -> Result<(), Error> {
+    let summary_path =
+        std::env::var_os("GITHUB_STEP_SUMMARY").ok_or(Error::GithubStepSummaryUnset)?;
+    let mut fh = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&summary_path)
+        .await
+        .map_err(|e| Error::OpenFile(summary_path.clone(), e))?;
+
+    fh.write_all(markdown.as_bytes())
+        .await
+        .map_err(|e| Error::WriteFile(summary_path, e))?;
+
+    Ok(())
+}
+
+/// Write the job summary, if running in GitHub Actions, warning (but not failing) if that doesn't work.
+pub(crate) async fn maybe_write_step_summary(markdown: &str) {
+    match append_step_summary(markdown).await {
+        Ok(()) | Err(Error::GithubStepSummaryUnset) => (),
+        Err(e) => tracing::warn!("Failed to write the job summary: {e}"),
+    }
+}
+
+/// How a push ended, for the job summary.
+pub(crate) enum ReleaseOutcome {
+    Published,
+    AlreadyExists,
+}
+
+/// The flakerefs a release can be depended on by, relative to FlakeHub's `/f/`.
+pub(crate) struct Flakerefs {
+    /// The latest release compatible with this one.
+    pub(crate) at_least: String,
+    /// Exactly this release.
+    pub(crate) exact: String,
+}
+
+impl Flakerefs {
+    pub(crate) fn new(upload_name: &str, release_version: &str) -> Self {
+        Self {
+            at_least: format!("{upload_name}/{release_version}"),
+            exact: format!("{upload_name}/={release_version}"),
+        }
+    }
+}
+
+/// The job summary for a release which was published (or already had been).
+pub(crate) struct ReleaseSummary<'a> {
+    pub(crate) outcome: ReleaseOutcome,
+    pub(crate) upload_name: &'a str,
+    pub(crate) release_version: &'a str,
+    pub(crate) flakerefs: &'a Flakerefs,
+    pub(crate) metadata: &'a ReleaseMetadata,
+    pub(crate) tarball_size: usize,
+    pub(crate) flakehub_host: &'a url::Url,
+}
+
+impl ReleaseSummary<'_> {
+    pub(crate) fn markdown(&self) -> String {
+        let frontend = frontend_url(self.flakehub_host);
+        let flake_page = format!(
+            "{frontend}/flake/{}/{}",
+            self.upload_name, self.release_version
+        );
+        let input_name = self
+            .upload_name
+            .rsplit('/')
+            .next()
+            .unwrap_or(self.upload_name)
+            .to_lowercase();
+
+        let mut md = String::new();
+        match self.outcome {
+            ReleaseOutcome::Published => {
+                let _ = writeln!(
+                    md,
+                    "## Published {} {}\n",
+                    self.upload_name, self.release_version
+                );
+            }
+            ReleaseOutcome::AlreadyExists => {
+                let _ = writeln!(
+                    md,
+                    "## {} {} was already published\n",
+                    self.upload_name, self.release_version
+                );
+                let _ = writeln!(
+                    md,
+                    "A release of revision `{}` already exists, so it was not uploaded again.\n",
+                    self.metadata.revision
+                );
+            }
+        }
+
+        let labels = if self.metadata.labels.is_empty() {
+            "_none_".to_string()
+        } else {
+            self.metadata
+                .labels
+                .iter()
+                .map(|label| format!("`{label}`"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let license = match &self.metadata.spdx_identifier {
+            Some(spdx) => format!("`{spdx}`"),
+            None => "_none_".to_string(),
+        };
+
+        let _ = writeln!(md, "| | |\n|---|---|");
+        let _ = writeln!(md, "| Flake | [{}]({flake_page}) |", self.upload_name);
+        let _ = writeln!(md, "| Version | `{}` |", self.release_version);
+        let _ = writeln!(md, "| Revision | `{}` |", self.metadata.revision);
+        let _ = writeln!(md, "| Visibility | {} |", self.metadata.visibility);
+        let _ = writeln!(md, "| Labels | {labels} |");
+        let _ = writeln!(md, "| License | {license} |");
+        let _ = writeln!(md, "| Tarball size | {} |", human_size(self.tarball_size));
+
+        let (per_system, other) = output_categories(&self.metadata.outputs.inventory);
+        if !per_system.is_empty() || !other.is_empty() {
+            let _ = writeln!(md, "\n### Outputs\n");
+            let _ = writeln!(md, "| System | Outputs |\n|---|---|");
+            for (system, outputs) in &per_system {
+                let outputs: Vec<String> = outputs.iter().map(|o| format!("`{o}`")).collect();
+                let _ = writeln!(md, "| `{system}` | {} |", outputs.join(", "));
+            }
+            if !other.is_empty() {
+                let outputs: Vec<String> = other.iter().map(|o| format!("`{o}`")).collect();
+                let _ = writeln!(md, "| _all_ | {} |", outputs.join(", "));
+            }
+        }
+
+        let _ = writeln!(md, "\n### Usage\n");
+        let _ = writeln!(md, "The latest compatible release:\n");
+        let _ = writeln!(
+            md,
+            "```nix\ninputs.{input_name}.url = \"{frontend}/f/{}\";\n```\n",
+            self.flakerefs.at_least
+        );
+        let _ = writeln!(md, "Exactly this release:\n");
+        let _ = writeln!(
+            md,
+            "```nix\ninputs.{input_name}.url = \"{frontend}/f/{}\";\n```",
+            self.flakerefs.exact
+        );
+
+        md
+    }
+}
+
+/// The job summary for a push which failed, listing the chain of errors which caused it.
+pub(crate) fn failure_summary(error: &color_eyre::eyre::Report) -> String {
+    let mut md = String::from("## flakehub-push failed\n\n```\n");
+    for (i, cause) in error.chain().enumerate() {
+        if i > 0 {
+            md.push_str("\nCaused by:\n");
+        }
+        let _ = writeln!(md, "{}", cause.to_string().trim());
+    }
+    md.push_str("```\n");
+    md
+}
+
+/// The FlakeHub web frontend corresponding to an API host, like `https://flakehub.com` for `https://api.flakehub.com`.
+fn frontend_url(api_host: &url::Url) -> String {
+    let mut frontend = api_host.clone();
+    if let Some(host) = api_host.host_str().and_then(|h| h.strip_prefix("api.")) {
+        let _ = frontend.set_host(Some(host));
+    }
+    frontend.as_str().trim_end_matches('/').to_string()
+}
+
+/// Group the inventory into which outputs each system has, and the outputs which aren't per-system.
+fn output_categories(
+    inventory: &BTreeMap<String, InventoryItem>,
+) -> (BTreeMap<String, BTreeSet<String>>, BTreeSet<String>) {
+    let mut per_system: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    let mut other = BTreeSet::new();
+
+    for (output, item) in inventory {
+        match item {
+            InventoryItem::Collection(collection)
+                if PER_SYSTEM_OUTPUTS.contains(&output.as_str()) =>
+            {
+                for system in collection.children.keys() {
+                    per_system
+                        .entry(system.clone())
+                        .or_default()
+                        .insert(output.clone());
+                }
+            }
+            _ => {
+                other.insert(output.clone());
+            }
+        }
+    }
+
+    (per_system, other)
+}
+
+fn human_size(bytes: usize) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
 fn escape_key_value<'a>(key: &'a str, value: &'a str) -> Result<String, Error> {
     // see: https://github.com/actions/toolkit/blob/6dd369c0e648ed58d0ead326cf2426906ea86401/packages/core/src/file-command.ts#L27-L47
     let delimiter = format!("ghadelimiter_{}", uuid::Uuid::new_v4());
@@ -52,3 +311,18 @@ fn escape_key_value<'a>(key: &'a str, value: &'a str) -> Result<String, Error> {
         "{key}<<{delimiter}{eol}{value}{eol}{delimiter}{eol}"
     ))
 }
+
+/// Escape the message portion of a workflow command, so multi-line messages are preserved.
+// see: https://github.com/actions/toolkit/blob/6dd369c0e648ed58d0ead326cf2426906ea86401/packages/core/src/command.ts#L80-L94
+pub(crate) fn escape_data(data: &str) -> String {
+    data.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escape a property value (like `title=...`) of a workflow command.
+pub(crate) fn escape_property(property: &str) -> String {
+    escape_data(property)
+        .replace(':', "%3A")
+        .replace(',', "%2C")
+}
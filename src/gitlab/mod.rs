@@ -1,5 +1,14 @@
+use std::path::PathBuf;
+
 use color_eyre::eyre::WrapErr;
 
+use crate::{
+    cli::FlakeHubPushCli,
+    git_context::GitContext,
+    provider::{Authenticator, Provider, Repository},
+    revision_info::RevisionInfo,
+};
+
 #[tracing::instrument(skip_all, fields(audience = tracing::field::Empty))]
 pub(crate) async fn get_runner_bearer_token() -> color_eyre::Result<String> {
     // github allows you to at-runtime change the audience of the token
@@ -13,3 +22,70 @@ pub(crate) async fn get_runner_bearer_token() -> color_eyre::Result<String> {
 
     Ok(token)
 }
+
+/// GitLab CI.
+pub(crate) struct GitLab;
+
+#[async_trait::async_trait]
+impl Provider for GitLab {
+    fn name(&self) -> &'static str {
+        "gitlab"
+    }
+
+    fn detect(&self, _cli: &FlakeHubPushCli) -> Vec<String> {
+        crate::provider::set_variables(&["GITLAB_CI"])
+    }
+
+    fn backfill(&self, cli: &mut FlakeHubPushCli) {
+        // https://docs.gitlab.com/ee/ci/variables/predefined_variables.html
+
+        if cli.git_root.0.is_none() {
+            let env_key: &str = "CI_PROJECT_DIR";
+            if let Ok(env_val) = std::env::var(env_key) {
+                tracing::debug!(git_root = %env_val, "Set via `${env_key}`");
+                cli.git_root.0 = Some(PathBuf::from(env_val));
+            }
+        }
+
+        if cli.repository.0.is_none() {
+            let env_key = "CI_PROJECT_ID";
+            if let Ok(env_val) = std::env::var(env_key) {
+                tracing::debug!(repository = %env_val, "Set via `${env_key}`");
+                cli.repository.0 = Some(env_val);
+            }
+        }
+
+        // TODO(review): this... isn't really a "tag" for github either, but I think maybe that's intentional?
+        if cli.tag.0.is_none() {
+            let env_key = "CI_COMMIT_REF_NAME";
+            if let Ok(env_val) = std::env::var(env_key) {
+                tracing::debug!(repository = %env_val, "Set via `${env_key}`");
+                cli.tag.0 = Some(env_val);
+            }
+        }
+    }
+
+    async fn prepare(
+        &self,
+        cli: &FlakeHubPushCli,
+        _client: &reqwest::Client,
+        _repository: &Repository,
+        local_rev_info: RevisionInfo,
+    ) -> color_eyre::Result<(GitContext, Box<dyn Authenticator>)> {
+        let git_ctx = GitContext::from_cli_and_gitlab(cli, local_rev_info).await?;
+
+        Ok((git_ctx, Box::new(RunnerToken)))
+    }
+}
+
+/// The `GITLAB_JWT_ID_TOKEN` configured for the job.
+struct RunnerToken;
+
+#[async_trait::async_trait]
+impl Authenticator for RunnerToken {
+    async fn bearer_token(&self) -> color_eyre::Result<String> {
+        get_runner_bearer_token()
+            .await
+            .wrap_err("Getting upload bearer token from GitLab")
+    }
+}
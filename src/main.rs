@@ -5,24 +5,44 @@ use color_eyre::eyre::{eyre, Result};
 use error::Error;
 use http::StatusCode;
 use reqwest::Response;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::Instrument;
 
 use crate::{
     flakehub_client::{FlakeHubClient, StageResult},
+    github_actions::{Flakerefs, ReleaseOutcome, ReleaseSummary},
     push_context::PushContext,
+    timings::Phase,
 };
+mod api_token;
+mod azure;
+mod buildkite;
+mod circleci;
 mod cli;
+mod cloudbuild;
+mod dev_jwt_issuer;
+mod doctor;
 mod error;
+mod evaluation;
+mod flake_check;
 mod flake_info;
 mod flakehub_auth_fake;
 mod flakehub_client;
+mod generic;
 mod git_context;
+mod gitea;
 mod github;
 mod github_actions;
 mod gitlab;
+mod nix;
+mod provider;
 mod push_context;
 mod release_metadata;
 mod revision_info;
 mod s3;
+mod timings;
+mod token_claims;
+mod whoami;
 
 const DEFAULT_ROLLING_PREFIX: &str = "0.1";
 
@@ -54,21 +74,91 @@ async fn main() -> Result<std::process::ExitCode> {
         })
         .install()?;
 
-    match execute().await {
-        Ok(exit) => Ok(exit),
+    let cli = cli::FlakeHubPushCli::parse();
+    let telemetry = cli
+        .instrumentation
+        .setup(provider::github_workflow_commands(&cli))?;
+    if let Some(github_token) = &cli.github_token.0 {
+        telemetry.add_mask(github_token);
+    }
+
+    // A root span, so everything from one push ends up in a single trace. Only when exporting
+    // traces, since the fmt layers would otherwise prefix every line with it.
+    let span = if cli.instrumentation.otlp_endpoint.is_some() {
+        tracing::info_span!(
+            "flakehub-push",
+            version = env!("CARGO_PKG_VERSION"),
+            otel.status_code = tracing::field::Empty,
+            otel.status_description = tracing::field::Empty,
+        )
+    } else {
+        tracing::Span::none()
+    };
+
+    // Dropping `execute` on a signal also terminates any running Nix processes.
+    let result = tokio::select! {
+        result = execute(cli, &telemetry).instrument(span.clone()) => result,
+        interrupted = interrupted() => Err(interrupted.into()),
+    };
+
+    let (exit, report) = match result {
+        Ok(exit) => (exit, None),
         Err(error) => {
-            if let Some(known_error) = error.downcast_ref::<Error>() {
-                known_error.maybe_github_actions_annotation()
+            let known_error = error::find(&error);
+            if let Some(known_error) = known_error.filter(|_| telemetry.workflow_commands) {
+                known_error.github_actions_annotation()
             }
-            Err(error)
+            // A conflicting release was already summarized, along with the release it conflicts with
+            if !matches!(known_error, Some(Error::Conflict { .. })) {
+                github_actions::maybe_write_step_summary(
+                    &telemetry.redact(&github_actions::failure_summary(&error)),
+                )
+                .await;
+            }
+            // Formatted before the span is marked as failed, or its span trace would repeat the error
+            let report = format!("{error:?}");
+            span.record("otel.status_code", "error");
+            span.record("otel.status_description", format!("{error:#}"));
+            (error::exit_code(&error), Some(report))
         }
+    };
+    // The error's span trace holds on to the root span, so it only closes once the error is gone
+    drop(span);
+
+    telemetry.timings.log_summary();
+    telemetry.shutdown();
+
+    if let Some(report) = report {
+        // Reported like returning the error would, but with an exit code wrappers can branch on
+        eprintln!("Error: {}", telemetry.redact(&report));
     }
+    Ok(exit)
 }
 
-async fn execute() -> Result<std::process::ExitCode> {
-    let mut cli = cli::FlakeHubPushCli::parse();
-    cli.instrumentation.setup()?;
+/// Explain the token FlakeHub rejected, since a 401 usually means a misconfigured audience or an expired token.
+///
+/// That's the token last sent, which may have been re-acquired since the push started.
+async fn explain_rejected_token(fhclient: &FlakeHubClient, flakehub_host: &url::Url) {
+    if let Some(token) = fhclient.current_token().await {
+        tracing::error!("{}", token_claims::explain(&token, flakehub_host));
+    }
+}
 
+async fn execute(
+    cli: cli::FlakeHubPushCli,
+    telemetry: &cli::Telemetry,
+) -> Result<std::process::ExitCode> {
+    match cli.command {
+        Some(cli::Command::Doctor) => doctor::run(cli, telemetry).await,
+        Some(cli::Command::Whoami { json }) => whoami::run(cli, json, telemetry).await,
+        None => push(cli, telemetry).await,
+    }
+}
+
+async fn push(
+    mut cli: cli::FlakeHubPushCli,
+    telemetry: &cli::Telemetry,
+) -> Result<std::process::ExitCode> {
     // NOTE(cole-h): If --dest-dir is passed, we're intentionally avoiding doing any actual
     // networking (i.e. for FlakeHub and GitHub)
     if let Some(dest_dir) = &cli.dest_dir.0 {
@@ -85,7 +175,7 @@ async fn execute() -> Result<std::process::ExitCode> {
         let release_json_name = format!("{release_version}.json");
 
         let (release_metadata, tarball) =
-            release_metadata::ReleaseMetadata::new(&cli, &git_ctx, None).await?;
+            release_metadata::ReleaseMetadata::new(&cli, &git_ctx, None, telemetry).await?;
 
         std::fs::create_dir_all(dest_dir)?;
 
@@ -104,36 +194,45 @@ async fn execute() -> Result<std::process::ExitCode> {
         return Ok(ExitCode::SUCCESS);
     }
 
-    let ctx = PushContext::from_cli_and_env(&mut cli).await?;
+    let ctx = PushContext::from_cli_and_env(&mut cli, telemetry).await?;
+
+    let flakehub_host = ctx.flakehub_host.clone();
+    let fhclient = ctx.fhclient;
 
     // Acquire the auth token *after* PushContext construction (which includes
-    // Nix evaluation via ReleaseMetadata::new). This ensures short-lived OIDC
-    // tokens are fresh when first used.
-    let (auth_token, ctx) = ctx.acquire_auth_token().await?;
-
-    let fhclient = FlakeHubClient::new(ctx.flakehub_host, auth_token)?;
-
-    let response = fhclient.token_status().await?;
-    if let Err(e) = response.error_for_status() {
-        let was_client_error = e.status().is_some_and(|x| x.is_client_error());
-        if std::env::var("GITHUB_ACTIONS").is_ok() {
-            if was_client_error {
-                tracing::error!("FlakeHub Unauthenticated: {}", e);
+    // Nix evaluation via ReleaseMetadata::new), unless `--explain-token` already
+    // did. This ensures short-lived OIDC tokens are fresh when first used. The
+    // client re-acquires it if it's about to expire later, like after a slow upload.
+    telemetry
+        .timings
+        .phase(Phase::Auth, fhclient.bearer_token())
+        .await?;
+
+    let response = telemetry
+        .timings
+        .phase(Phase::Auth, fhclient.token_status())
+        .await?;
+    if let Err(e) = FlakeHubClient::check_token_status(response).await {
+        if matches!(e, Error::Unauthorized(_)) {
+            explain_rejected_token(&fhclient, &flakehub_host).await;
+            if telemetry.workflow_commands {
                 github::print_unauthenticated_error();
-            } else {
-                println!("::error title=FlakeHub: Unauthenticated::Unable to authenticate to FlakeHub. {}", e);
             }
         }
-        return Err(e.into());
+        return Err(e)?;
     }
 
     // "upload.rs" - stage the release
-    let stage_result = fhclient
-        .release_stage(
-            &ctx.upload_name,
-            &ctx.release_version,
-            &ctx.metadata,
-            &ctx.tarball,
+    let stage_result = telemetry
+        .timings
+        .phase(
+            Phase::Stage,
+            fhclient.release_stage(
+                &ctx.upload_name,
+                &ctx.release_version,
+                &ctx.metadata,
+                &ctx.tarball,
+            ),
         )
         .await;
 
@@ -160,7 +259,21 @@ async fn execute() -> Result<std::process::ExitCode> {
                         release_version = &ctx.release_version,
                     );
 
-                    set_release_outputs(&ctx.upload_name, &ctx.release_version).await;
+                    let flakerefs = Flakerefs::new(&ctx.upload_name, &ctx.release_version);
+                    set_release_outputs(&ctx.upload_name, &ctx.release_version, &flakerefs).await;
+                    github_actions::maybe_write_step_summary(
+                        &ReleaseSummary {
+                            outcome: ReleaseOutcome::AlreadyExists,
+                            upload_name: &ctx.upload_name,
+                            release_version: &ctx.release_version,
+                            flakerefs: &flakerefs,
+                            metadata: &ctx.metadata,
+                            tarball_size: ctx.tarball.bytes.len(),
+                            flakehub_host: &flakehub_host,
+                        }
+                        .markdown(),
+                    )
+                    .await;
 
                     if ctx.error_if_release_conflicts {
                         return Err(Error::Conflict {
@@ -173,6 +286,7 @@ async fn execute() -> Result<std::process::ExitCode> {
                     }
                 }
                 StatusCode::UNAUTHORIZED => {
+                    explain_rejected_token(&fhclient, &flakehub_host).await;
                     return Err(Error::Unauthorized(response_text(response).await))?;
                 }
                 StatusCode::BAD_REQUEST => {
@@ -193,10 +307,20 @@ async fn execute() -> Result<std::process::ExitCode> {
     };
 
     // upload tarball to s3
-    s3::upload_release_to_s3(stage_result.s3_upload_url, ctx.tarball).await?;
+    let tarball_size = ctx.tarball.bytes.len();
+    telemetry
+        .timings
+        .phase(
+            Phase::Upload,
+            s3::upload_release_to_s3(stage_result.s3_upload_url, ctx.tarball),
+        )
+        .await?;
 
     // "publish.rs" - publish the release after upload
-    fhclient.release_publish(stage_result.uuid).await?;
+    telemetry
+        .timings
+        .phase(Phase::Publish, fhclient.release_publish(stage_result.uuid))
+        .await?;
 
     tracing::info!(
         "Successfully released new version of {}/{}",
@@ -204,11 +328,50 @@ async fn execute() -> Result<std::process::ExitCode> {
         ctx.release_version
     );
 
-    set_release_outputs(&ctx.upload_name, &ctx.release_version).await;
+    let flakerefs = Flakerefs::new(&ctx.upload_name, &ctx.release_version);
+    set_release_outputs(&ctx.upload_name, &ctx.release_version, &flakerefs).await;
+    github_actions::maybe_write_step_summary(
+        &ReleaseSummary {
+            outcome: ReleaseOutcome::Published,
+            upload_name: &ctx.upload_name,
+            release_version: &ctx.release_version,
+            flakerefs: &flakerefs,
+            metadata: &ctx.metadata,
+            tarball_size,
+            flakehub_host: &flakehub_host,
+        }
+        .markdown(),
+    )
+    .await;
 
     Ok(ExitCode::SUCCESS)
 }
 
+/// Resolves once flakehub-push is asked to stop with `SIGINT` or `SIGTERM`.
+///
+/// Never resolves if the signal handlers can't be registered, so the push isn't stopped over it.
+async fn interrupted() -> Error {
+    let (mut interrupt, mut terminate) = match (
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    ) {
+        (Ok(interrupt), Ok(terminate)) => (interrupt, terminate),
+        (Err(e), _) | (_, Err(e)) => {
+            tracing::warn!(
+                "Failed to handle SIGINT and SIGTERM, so they won't stop Nix cleanly: {e}"
+            );
+            return std::future::pending().await;
+        }
+    };
+
+    let signal = tokio::select! {
+        _ = interrupt.recv() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    };
+
+    Error::Interrupted(signal.to_string())
+}
+
 async fn response_text(res: Response) -> String {
     if let Ok(message) = res.text().await {
         message
@@ -238,18 +401,12 @@ impl Display for Visibility {
     }
 }
 
-async fn set_release_outputs(upload_name: &str, release_version: &str) {
+async fn set_release_outputs(upload_name: &str, release_version: &str, flakerefs: &Flakerefs) {
     let outputs = [
         ("flake_name", upload_name),
         ("flake_version", release_version),
-        (
-            "flakeref_at_least",
-            &format!("{}/{}", upload_name, release_version),
-        ),
-        (
-            "flakeref_exact",
-            &format!("{}/={}", upload_name, release_version),
-        ),
+        ("flakeref_at_least", &flakerefs.at_least),
+        ("flakeref_exact", &flakerefs.exact),
     ];
     for (output_name, value) in outputs.into_iter() {
         if let Err(e) = github_actions::set_output(output_name, value).await {
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::process::{Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+
+use crate::cli::{FlakeHubPushCli, Telemetry};
+
+/// How long a timed out Nix process gets to exit after `SIGTERM`, before it is sent `SIGKILL`.
+const TERMINATION_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// The phases of a push which run Nix, each of which can be given its own timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub(crate) enum NixPhase {
+    Metadata,
+    Prefetch,
+    Evaluation,
+    LockCheck,
+    FlakeCheck,
+    Inspect,
+}
+
+impl Display for NixPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NixPhase::Metadata => f.write_str("metadata"),
+            NixPhase::Prefetch => f.write_str("prefetch"),
+            NixPhase::Evaluation => f.write_str("evaluation"),
+            NixPhase::LockCheck => f.write_str("lock-check"),
+            NixPhase::FlakeCheck => f.write_str("flake-check"),
+            NixPhase::Inspect => f.write_str("inspect"),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    #[error("The {phase} phase timed out after {}s running `{command}`", timeout.as_secs())]
+    TimedOut {
+        phase: NixPhase,
+        command: String,
+        timeout: Duration,
+    },
+}
+
+/// How to invoke Nix, shared by every `nix` subprocess flakehub-push runs.
+#[derive(Clone)]
+pub(crate) struct Nix {
+    binary: PathBuf,
+    /// Arguments placed before the subcommand of every invocation, like `--offline`.
+    args: Vec<String>,
+    /// Extra `nix.conf` settings passed via `NIX_CONFIG` rather than arguments, since they may contain secrets.
+    config: Option<String>,
+    /// The timeout of any phase not in `phase_timeouts`.
+    timeout: Option<Duration>,
+    phase_timeouts: HashMap<NixPhase, Duration>,
+    /// When each phase which has started running commands times out, shared by every clone so a phase's
+    /// commands share its timeout, even when they run concurrently.
+    deadlines: Arc<Mutex<HashMap<NixPhase, Instant>>>,
+}
+
+impl std::fmt::Debug for Nix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Nix")
+            .field("binary", &self.binary)
+            .field("args", &self.args)
+            .field("config", &self.config.as_ref().map(|_| "<redacted>"))
+            .field("timeout", &self.timeout)
+            .field("phase_timeouts", &self.phase_timeouts)
+            .finish()
+    }
+}
+
+impl Nix {
+    pub(crate) fn from_cli(cli: &FlakeHubPushCli, telemetry: &Telemetry) -> Result<Self> {
+        let mut args = Vec::new();
+
+        if cli.offline {
+            args.push("--offline".to_string());
+        }
+
+        if cli.accept_flake_config {
+            args.push("--accept-flake-config".to_string());
+        }
+
+        for option in cli.nix_options.iter().filter(|v| !v.is_empty()) {
+            let (name, value) = option.split_once('=').ok_or_else(|| {
+                eyre!("`--nix-option` must be formatted like `name=value`, got `{option}`")
+            })?;
+            args.push("--option".to_string());
+            args.push(name.trim().to_string());
+            args.push(value.trim().to_string());
+        }
+
+        args.extend(cli.nix_args.iter().filter(|v| !v.is_empty()).cloned());
+
+        let config = match &cli.nix_access_tokens_file.0 {
+            Some(path) => {
+                let tokens = std::fs::read_to_string(path).wrap_err_with(|| {
+                    eyre!("Reading `--nix-access-tokens-file` {}", path.display())
+                })?;
+                let tokens: Vec<&str> = tokens.split_whitespace().collect();
+                for token in &tokens {
+                    telemetry.add_mask(token.split_once('=').map_or(*token, |(_, t)| t));
+                }
+                Some(format!("access-tokens = {}", tokens.join(" ")))
+            }
+            None => None,
+        };
+
+        let mut phase_timeouts = HashMap::new();
+        for phase_timeout in cli.nix_phase_timeouts.iter().filter(|v| !v.is_empty()) {
+            let (phase, seconds) = phase_timeout.split_once('=').ok_or_else(|| {
+                eyre!("`--nix-phase-timeout` must be formatted like `phase=seconds`, got `{phase_timeout}`")
+            })?;
+            let phase = <NixPhase as clap::ValueEnum>::from_str(phase.trim(), true)
+                .map_err(|e| eyre!("Invalid phase in `--nix-phase-timeout`: {e}"))?;
+            let seconds: u64 = seconds
+                .trim()
+                .parse()
+                .wrap_err_with(|| eyre!("Invalid timeout for the {phase} phase"))?;
+            phase_timeouts.insert(phase, Duration::from_secs(seconds));
+        }
+
+        let nix = Self {
+            binary: cli.nix_binary.clone(),
+            args,
+            config,
+            timeout: cli.nix_timeout.0.map(Duration::from_secs),
+            phase_timeouts,
+            deadlines: Arc::default(),
+        };
+        tracing::debug!(?nix, "Configured Nix");
+
+        Ok(nix)
+    }
+
+    /// A `nix` command with the configured binary, arguments and settings applied.
+    pub(crate) fn command(&self) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new(&self.binary);
+        command.args(&self.args);
+
+        if let Some(config) = &self.config {
+            let mut nix_config = std::env::var("NIX_CONFIG").unwrap_or_default();
+            if !nix_config.is_empty() {
+                nix_config.push('\n');
+            }
+            nix_config.push_str(config);
+            command.env("NIX_CONFIG", nix_config);
+        }
+
+        command
+    }
+
+    fn timeout(&self, phase: NixPhase) -> Option<Duration> {
+        self.phase_timeouts.get(&phase).copied().or(self.timeout)
+    }
+
+    /// How long the commands of `phase` have left, which starts counting down with its first command.
+    fn remaining(&self, phase: NixPhase) -> Option<Duration> {
+        let timeout = self.timeout(phase)?;
+        let now = Instant::now();
+        let deadline = match self.deadlines.lock() {
+            Ok(mut deadlines) => *deadlines.entry(phase).or_insert(now + timeout),
+            Err(_) => now + timeout,
+        };
+        Some(deadline.saturating_duration_since(now))
+    }
+
+    /// Run `command` to completion, collecting its output.
+    ///
+    /// The command runs in its own process group, so if `phase` exceeds its timeout (or this future is dropped,
+    /// for example because flakehub-push was interrupted) the whole process tree is terminated. The timeout is
+    /// shared by every command of the phase, like the evaluation of each system.
+    pub(crate) async fn output(
+        &self,
+        phase: NixPhase,
+        command: &mut tokio::process::Command,
+    ) -> Result<Output> {
+        let description = describe(command);
+
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .process_group(0);
+
+        let child = command
+            .spawn()
+            .wrap_err_with(|| eyre!("Failed to execute `{description}`"))?;
+        let mut guard = ProcessGroupGuard(child.id());
+
+        let wait = child.wait_with_output();
+        tokio::pin!(wait);
+
+        let (Some(timeout), Some(remaining)) = (self.timeout(phase), self.remaining(phase)) else {
+            let output = wait.await;
+            guard.disarm();
+            return output.wrap_err_with(|| eyre!("Waiting for `{description}`"));
+        };
+
+        match tokio::time::timeout(remaining, &mut wait).await {
+            Ok(output) => {
+                guard.disarm();
+                output.wrap_err_with(|| eyre!("Waiting for `{description}`"))
+            }
+            Err(_) => {
+                tracing::warn!(%phase, command = %description, "Timed out, terminating Nix");
+                guard.signal(libc::SIGTERM);
+                if tokio::time::timeout(TERMINATION_GRACE_PERIOD, &mut wait)
+                    .await
+                    .is_ok()
+                {
+                    guard.disarm();
+                }
+
+                Err(Error::TimedOut {
+                    phase,
+                    command: description,
+                    timeout,
+                })?
+            }
+        }
+    }
+}
+
+/// Kills the process group of a child process when dropped, unless disarmed.
+struct ProcessGroupGuard(Option<u32>);
+
+impl ProcessGroupGuard {
+    fn signal(&self, signal: libc::c_int) {
+        if let Some(pid) = self.0.and_then(|pid| libc::pid_t::try_from(pid).ok()) {
+            // SAFETY: the child is the leader of its own process group, so this only signals it and its descendants
+            unsafe {
+                libc::kill(-pid, signal);
+            }
+        }
+    }
+
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        self.signal(libc::SIGKILL);
+    }
+}
+
+/// Render a command for error messages, quoted so it can be pasted into a shell, but without the environment
+/// (which may contain secrets).
+pub(crate) fn describe(command: &tokio::process::Command) -> String {
+    let command = command.as_std();
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn shell_quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,@+#%".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use super::{describe, Error, Nix, NixPhase};
+
+    fn sh(timeout: Duration) -> Nix {
+        Nix {
+            binary: "sh".into(),
+            args: Vec::new(),
+            config: None,
+            timeout: Some(timeout),
+            phase_timeouts: HashMap::new(),
+            deadlines: Default::default(),
+        }
+    }
+
+    fn timed_out(result: color_eyre::Result<std::process::Output>) -> bool {
+        matches!(
+            result.map_err(|e| e.downcast::<Error>()),
+            Err(Ok(Error::TimedOut { .. }))
+        )
+    }
+
+    #[test]
+    fn describes_commands_for_a_shell() {
+        let mut command = tokio::process::Command::new("nix");
+        command
+            .arg("eval")
+            .arg("path:/tmp/flake#packages")
+            .arg("--apply")
+            .arg("x: builtins.attrNames x")
+            .arg("it's");
+        assert_eq!(
+            describe(&command),
+            r#"nix eval path:/tmp/flake#packages --apply 'x: builtins.attrNames x' 'it'\''s'"#
+        );
+    }
+
+    #[tokio::test]
+    async fn kills_a_command_which_times_out() {
+        let nix = sh(Duration::from_millis(200));
+        let start = Instant::now();
+        let result = nix
+            .output(
+                NixPhase::Evaluation,
+                nix.command().arg("-c").arg("sleep 30"),
+            )
+            .await;
+        assert!(timed_out(result));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn shares_the_timeout_across_a_phase() {
+        let nix = sh(Duration::from_millis(500));
+        let sleep = |nix: &Nix| {
+            let mut command = nix.command();
+            command.arg("-c").arg("sleep 0.3");
+            command
+        };
+
+        let first = nix.output(NixPhase::Evaluation, &mut sleep(&nix)).await;
+        assert!(first.unwrap().status.success());
+        // Each command is within the timeout, but together they aren't
+        let second = nix
+            .clone()
+            .output(NixPhase::Evaluation, &mut sleep(&nix))
+            .await;
+        assert!(timed_out(second));
+
+        // Other phases have their own timeout
+        let other = nix.output(NixPhase::Metadata, &mut sleep(&nix)).await;
+        assert!(other.unwrap().status.success());
+    }
+}
@@ -0,0 +1,185 @@
+use color_eyre::eyre::{eyre, Result};
+
+use crate::{cli::FlakeHubPushCli, git_context::GitContext, revision_info::RevisionInfo};
+
+/// The repository being pushed, from `--repository` (and `--name`).
+pub(crate) struct Repository {
+    /// The repository exactly as it was given, like `determinatesystems/flakehub-push`.
+    pub(crate) full_name: String,
+    pub(crate) owner: String,
+    pub(crate) name: String,
+}
+
+/// An environment flakehub-push can run in, like a CI system, which knows how to fill in details about the
+/// repository and how to authenticate to FlakeHub from there.
+#[async_trait::async_trait]
+pub(crate) trait Provider: Send + Sync {
+    /// A short, stable name for this environment, like `github`.
+    fn name(&self) -> &'static str;
+
+    /// Why flakehub-push seems to be running in this environment, like which variables are set, if it does.
+    fn detect(&self, cli: &FlakeHubPushCli) -> Vec<String>;
+
+    /// Fill in whatever wasn't passed on the command line from the environment.
+    fn backfill(&self, _cli: &mut FlakeHubPushCli) {}
+
+    /// Gather what's known about the repository, and prepare to authenticate.
+    ///
+    /// Tokens aren't requested yet, since they may be short-lived and Nix evaluation comes first.
+    async fn prepare(
+        &self,
+        cli: &FlakeHubPushCli,
+        client: &reqwest::Client,
+        repository: &Repository,
+        local_rev_info: RevisionInfo,
+    ) -> Result<(GitContext, Box<dyn Authenticator>)>;
+
+    /// Whether this environment understands GitHub's workflow commands, like `::warning::`.
+    fn github_workflow_commands(&self) -> bool {
+        false
+    }
+}
+
+/// Acquires a bearer token to authenticate to FlakeHub with.
+#[async_trait::async_trait]
+pub(crate) trait Authenticator: Send + Sync {
+    async fn bearer_token(&self) -> Result<String>;
+}
+
+/// Every environment flakehub-push knows about, in the order they're detected.
+pub(crate) fn providers() -> Vec<Box<dyn Provider>> {
+    vec![
+        // Gitea and Forgejo Actions also set `GITHUB_ACTION`, so these need to be checked first
+        Box::new(crate::gitea::Gitea),
+        Box::new(crate::github::GitHub),
+        Box::new(crate::gitlab::GitLab),
+        Box::new(crate::buildkite::Buildkite),
+        Box::new(crate::circleci::CircleCI),
+        Box::new(crate::azure::AzurePipelines),
+        Box::new(crate::cloudbuild::CloudBuild),
+        Box::new(crate::generic::Generic),
+        // Explicitly asking for local development wins over a token meant for pushing from anywhere
+        Box::new(crate::flakehub_auth_fake::LocalGitHub),
+        Box::new(crate::api_token::ApiToken),
+    ]
+}
+
+/// The environments `--ci-provider` can choose, each named like its [`Provider::name`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum CiProvider {
+    Gitea,
+    Github,
+    Gitlab,
+    Buildkite,
+    Circleci,
+    AzurePipelines,
+    CloudBuild,
+    Generic,
+    Local,
+    Token,
+}
+
+impl CiProvider {
+    fn is(self, provider: &dyn Provider) -> bool {
+        <Self as clap::ValueEnum>::from_str(provider.name(), false) == Ok(self)
+    }
+}
+
+/// Whether the environment reads GitHub's workflow commands (like `::error::`) from the log, decided before
+/// anything is logged: the one chosen with `--ci-provider`, or else the one which would be detected.
+pub(crate) fn github_workflow_commands(cli: &FlakeHubPushCli) -> bool {
+    let mut providers = providers().into_iter();
+    let provider = match cli.ci_provider.0 {
+        Some(ci_provider) => providers.find(|p| ci_provider.is(p.as_ref())),
+        None => providers.find(|p| !p.detect(cli).is_empty()),
+    };
+    provider.is_some_and(|p| p.github_workflow_commands())
+}
+
+/// Choose the environment flakehub-push is running in, either from `--ci-provider` or by detecting it.
+pub(crate) fn detect(cli: &FlakeHubPushCli) -> Result<Box<dyn Provider>> {
+    let providers = providers();
+
+    let provider = if let Some(ci_provider) = cli.ci_provider.0 {
+        let provider = providers
+            .into_iter()
+            .find(|p| ci_provider.is(p.as_ref()))
+            .ok_or_else(|| eyre!("No environment is named like `--ci-provider` {ci_provider:?}"))?;
+        tracing::info!(
+            "Using the `{}` environment, as set by `--ci-provider`",
+            provider.name()
+        );
+        provider
+    } else {
+        let mut detected = providers
+            .into_iter()
+            .map(|p| {
+                let evidence = p.detect(cli);
+                (p, evidence)
+            })
+            .filter(|(_, evidence)| !evidence.is_empty());
+
+        let Some((provider, evidence)) = detected.next() else {
+            // who knows what's going on, invalid
+            return Err(eyre!(
+                "can't determine execution environment, pass `--ci-provider`"
+            ));
+        };
+        tracing::info!(
+            "Detected the `{}` environment from {}",
+            provider.name(),
+            evidence.join(", ")
+        );
+        for (other, evidence) in detected {
+            tracing::info!(
+                "Also found {}, which suggests the `{}` environment; pass `--ci-provider` if `{}` is wrong",
+                evidence.join(", "),
+                other.name(),
+                provider.name()
+            );
+        }
+        provider
+    };
+
+    if (cli.jwt_issuer_uri.is_some() || cli.dev_jwt_issuer)
+        && provider.name() != crate::flakehub_auth_fake::LocalGitHub::NAME
+    {
+        // we're in CI and jwt_issuer_uri was specified, invalid
+        return Err(eyre!(
+            "specifying the jwt_issuer_uri or dev_jwt_issuer when running in CI is invalid"
+        ));
+    }
+
+    Ok(provider)
+}
+
+/// The variables among `names` which are set, as evidence for detecting an environment.
+pub(crate) fn set_variables(names: &[&str]) -> Vec<String> {
+    names
+        .iter()
+        .filter(|name| std::env::var_os(name).is_some())
+        .map(|name| format!("`${name}`"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::ValueEnum as _;
+
+    use super::{providers, CiProvider};
+
+    #[test]
+    fn every_provider_can_be_chosen() {
+        let providers = providers();
+        assert_eq!(providers.len(), CiProvider::value_variants().len());
+        for provider in &providers {
+            assert!(
+                CiProvider::value_variants()
+                    .iter()
+                    .any(|ci_provider| ci_provider.is(provider.as_ref())),
+                "`{}` can't be chosen with `--ci-provider`",
+                provider.name()
+            );
+        }
+    }
+}
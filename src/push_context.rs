@@ -1,40 +1,24 @@
-use color_eyre::eyre::{eyre, Context, Result};
+use color_eyre::eyre::{eyre, Result};
 
 use crate::{
-    build_http_client, cli::FlakeHubPushCli, flakehub_auth_fake, flakehub_client::Tarball,
-    git_context::GitContext, github::graphql::GithubGraphqlDataQuery,
-    release_metadata::ReleaseMetadata, revision_info::RevisionInfo,
+    build_http_client,
+    cli::{FlakeHubPushCli, Telemetry},
+    error::Error,
+    flakehub_client::{FlakeHubClient, Tarball},
+    git_context::GitContext,
+    provider::{Authenticator, Provider, Repository},
+    release_metadata::ReleaseMetadata,
+    revision_info::RevisionInfo,
+    timings::Phase,
+    token_claims,
 };
 
-#[derive(Clone)]
-pub enum ExecutionEnvironment {
-    GitHub,
-    GitLab,
-    LocalGitHub,
-    Generic,
-}
-
-/// Captures the data needed to acquire an auth token for a given execution
-/// environment. Token acquisition is deferred until after Nix evaluation
-/// completes, so that short-lived OIDC tokens (e.g. GitHub's ~5 min JWTs)
-/// are not stale by the time they are used.
-pub(crate) enum TokenContext {
-    GitHub {
-        host: url::Url,
-    },
-    GitLab,
-    Generic,
-    LocalGitHub {
-        jwt_issuer_uri: String,
-        project_owner: String,
-        repository: String,
-        github_graphql_data_result: crate::github::graphql::GithubGraphqlDataResult,
-    },
-}
-
 pub(crate) struct PushContext {
     pub(crate) flakehub_host: url::Url,
-    pub(crate) token_context: TokenContext,
+    /// Token acquisition is deferred until after Nix evaluation completes, so that short-lived OIDC tokens
+    /// (e.g. GitHub's ~5 min JWTs) are not stale by the time they are used. Unless `--explain-token` is set,
+    /// in which case the client re-acquires the token if it's about to expire.
+    pub(crate) fhclient: FlakeHubClient,
 
     // url components
     pub(crate) upload_name: String, // {org}/{project}
@@ -49,141 +33,37 @@ pub(crate) struct PushContext {
 }
 
 impl PushContext {
-    pub async fn from_cli_and_env(cli: &mut FlakeHubPushCli) -> Result<Self> {
-        // Take the opportunity to be able to populate/encrich data from the GitHub API
-        // this is used to augment user/discovered data, and is used for the faked JWT for local flakehub-push testing
-
+    pub async fn from_cli_and_env(
+        cli: &mut FlakeHubPushCli,
+        telemetry: &Telemetry,
+    ) -> Result<Self> {
         let client = build_http_client().build()?;
 
-        let exec_env = cli.execution_environment();
-
-        match exec_env.clone() {
-            ExecutionEnvironment::GitHub => {
-                cli.backfill_from_github_env();
-            }
-            ExecutionEnvironment::GitLab => {
-                cli.backfill_from_gitlab_env();
-            }
-            _ => {}
-        };
-
-        // STEP: determine and check 'repository' and 'upload_name'
-        // If the upload name is supplied by the user, ensure that it contains exactly
-        // one slash and no whitespace. Default to the repository name.
-        // notes for future readers:
-        // upload_name is derived from repository, unless set
-        // upload_name is then used for upload_name (and repository) there-after
-        // *except* in GitHub paths, where it's used to query the authoritative git_ctx and locally to fill the fake jwt
-
-        let Some(ref repository) = cli.repository.0 else {
-            return Err(eyre!("Could not determine repository name, pass `--repository` formatted like `determinatesystems/flakehub-push`"));
-        };
+        let Prepared {
+            provider,
+            upload_name,
+            git_ctx,
+            authenticator,
+        } = prepare(cli, &client).await?;
 
-        let (upload_name, project_owner, project_name) =
-            determine_names(&cli.name.0, repository, cli.disable_rename_subgroups)?;
-
-        let local_git_root = cli.resolve_local_git_root()?;
-        let local_rev_info = RevisionInfo::from_git_root(&local_git_root)?;
-
-        // "cli" and "git_ctx" are the user/env supplied info, augmented with data we might have fetched from github/gitlab apis
-
-        let (token_context, git_ctx) = match (&exec_env, &cli.jwt_issuer_uri) {
-            (ExecutionEnvironment::GitHub, None) => {
-                // GITHUB CI
-                let github_token = cli
-                    .github_token
-                    .0
-                    .clone()
-                    .expect("failed to get github token when running in GitHub Actions");
-
-                let Some(github_graphql_url) = cli.github_graphql_url.0.as_ref() else {
-                    return Err(eyre!("`--github-graphql-url` was not specified and could not be populated from the GITHUB_GRAPHQL_URL environment variable"));
-                };
-
-                let github_graphql_data_result = GithubGraphqlDataQuery::get(
-                    &client,
-                    github_graphql_url,
-                    &github_token,
-                    &project_owner,
-                    &project_name,
-                    cli.rev.0.as_ref().unwrap_or(&local_rev_info.revision),
-                )
-                .await?;
+        let release_version = cli.release_version(&git_ctx)?;
 
-                let git_ctx = GitContext::from_cli_and_github(cli, &github_graphql_data_result)?;
-
-                let token_ctx = TokenContext::GitHub {
-                    host: cli.host.clone(),
-                };
-
-                (token_ctx, git_ctx)
-            }
-            (ExecutionEnvironment::GitLab, None) => {
-                // GITLAB CI
-                let git_ctx = GitContext::from_cli_and_gitlab(cli, local_rev_info).await?;
-
-                (TokenContext::GitLab, git_ctx)
-            }
-            (ExecutionEnvironment::Generic, None) => {
-                // Generic CI (Semaphore, ...)
-                let git_ctx = GitContext::from_cli(cli, local_rev_info).await?;
-
-                (TokenContext::Generic, git_ctx)
-            }
-            (ExecutionEnvironment::LocalGitHub, Some(u)) => {
-                // LOCAL, DEV (aka emulating GITHUB)
-                let github_token = cli
-                    .github_token
-                    .0
-                    .clone()
-                    .expect("failed to get github token when running locally");
-
-                let Some(github_graphql_url) = cli.github_graphql_url.0.as_ref() else {
-                    return Err(eyre!("`--github-graphql-url` was not specified and could not be populated from the GITHUB_GRAPHQL_URL environment variable"));
-                };
-
-                let github_graphql_data_result = GithubGraphqlDataQuery::get(
-                    &client,
-                    github_graphql_url,
-                    &github_token,
-                    &project_owner,
-                    &project_name,
-                    cli.rev.0.as_ref().unwrap_or(&local_rev_info.revision),
-                )
+        let fhclient = FlakeHubClient::new(cli.host.clone(), authenticator, telemetry)?;
+        // Explained before evaluating the flake, which can take a while, so a misconfigured token shows up early
+        if cli.explain_token {
+            let token = telemetry
+                .timings
+                .phase(Phase::Auth, fhclient.bearer_token())
                 .await?;
-
-                let git_ctx: GitContext =
-                    GitContext::from_cli_and_github(cli, &github_graphql_data_result)?;
-
-                let token_ctx = TokenContext::LocalGitHub {
-                    jwt_issuer_uri: u.clone(),
-                    project_owner: project_owner.clone(),
-                    repository: repository.clone(),
-                    github_graphql_data_result,
-                };
-
-                (token_ctx, git_ctx)
-            }
-            (_, Some(_)) => {
-                // we're in (GitHub|GitLab) and jwt_issuer_uri was specified, invalid
-                return Err(eyre!(
-                    "specifying the jwt_issuer_uri when running in GitHub or GitLab is invalid"
-                ));
-            }
-            _ => {
-                // who knows what's going on, invalid
-                return Err(eyre!("can't determine execution environment"));
-            }
-        };
-
-        let release_version = cli.release_version(&git_ctx)?;
+            tracing::info!("{}", token_claims::explain(&token, &cli.host));
+        }
 
         let (release_metadata, flake_tarball) =
-            ReleaseMetadata::new(cli, &git_ctx, Some(&exec_env)).await?;
+            ReleaseMetadata::new(cli, &git_ctx, Some(provider.as_ref()), telemetry).await?;
 
         let ctx = Self {
             flakehub_host: cli.host.clone(),
-            token_context,
+            fhclient,
 
             upload_name,
             release_version,
@@ -196,75 +76,71 @@ impl PushContext {
 
         Ok(ctx)
     }
+}
 
-    /// Acquire the auth token for the current execution environment.
-    ///
-    /// This is intentionally called *after* PushContext construction (which
-    /// includes expensive Nix evaluation) so that short-lived OIDC tokens
-    /// are fresh when first used by FlakeHubClient.
-    pub async fn acquire_auth_token(self) -> Result<(String, Self)> {
-        // Destructure up front so we own all fields and can match on
-        // token_context by value (required for LocalGitHub which moves
-        // github_graphql_data_result into get_fake_bearer_token).
-        let PushContext {
-            flakehub_host,
-            token_context,
-            upload_name,
-            release_version,
-            error_if_release_conflicts,
-            metadata,
-            tarball,
-        } = self;
-
-        let (token, token_context) = match token_context {
-            TokenContext::GitHub { ref host } => {
-                let t = crate::github::get_actions_id_bearer_token(host)
-                    .await
-                    .wrap_err("Getting upload bearer token from GitHub")?;
-                (t, token_context)
-            }
-            TokenContext::GitLab => {
-                let t = crate::gitlab::get_runner_bearer_token()
-                    .await
-                    .wrap_err("Getting upload bearer token from GitLab")?;
-                (t, TokenContext::GitLab)
-            }
-            TokenContext::Generic => {
-                let t = std::env::var("FLAKEHUB_PUSH_OIDC_TOKEN")
-                    .with_context(|| "missing FLAKEHUB_PUSH_OIDC_TOKEN environment variable")?;
-                (t, TokenContext::Generic)
-            }
-            TokenContext::LocalGitHub {
-                jwt_issuer_uri,
-                project_owner,
-                repository,
-                github_graphql_data_result,
-            } => {
-                let t = flakehub_auth_fake::get_fake_bearer_token(
-                    &jwt_issuer_uri,
-                    &project_owner,
-                    &repository,
-                    github_graphql_data_result,
-                )
-                .await?;
-                // Use a sentinel since the token has been acquired and the
-                // graphql data has been consumed.
-                (t, TokenContext::Generic)
-            }
-        };
+/// The environment flakehub-push is running in, with what it found out about the repository and how to
+/// authenticate from there.
+pub(crate) struct Prepared {
+    pub(crate) provider: Box<dyn Provider>,
+    pub(crate) upload_name: String,
+    pub(crate) git_ctx: GitContext,
+    pub(crate) authenticator: Box<dyn Authenticator>,
+}
 
-        let ctx = PushContext {
-            flakehub_host,
-            token_context,
-            upload_name,
-            release_version,
-            error_if_release_conflicts,
-            metadata,
-            tarball,
-        };
+/// Detect the environment, fill in the configuration from it, and prepare to authenticate.
+pub(crate) async fn prepare(
+    cli: &mut FlakeHubPushCli,
+    client: &reqwest::Client,
+) -> Result<Prepared> {
+    // Take the opportunity to be able to populate/encrich data from the GitHub API
+    // this is used to augment user/discovered data, and is used for the faked JWT for local flakehub-push testing
 
-        Ok((token, ctx))
-    }
+    let provider = crate::provider::detect(cli)?;
+    prepare_with(provider, cli, client).await
+}
+
+/// Like [`prepare`], in the environment `provider` which was already detected.
+pub(crate) async fn prepare_with(
+    provider: Box<dyn Provider>,
+    cli: &mut FlakeHubPushCli,
+    client: &reqwest::Client,
+) -> Result<Prepared> {
+    provider.backfill(cli);
+
+    // STEP: determine and check 'repository' and 'upload_name'
+    // If the upload name is supplied by the user, ensure that it contains exactly
+    // one slash and no whitespace. Default to the repository name.
+    // notes for future readers:
+    // upload_name is derived from repository, unless set
+    // upload_name is then used for upload_name (and repository) there-after
+    // *except* in GitHub paths, where it's used to query the authoritative git_ctx and locally to fill the fake jwt
+
+    let Some(ref repository) = cli.repository.0 else {
+        return Err(Error::MissingConfiguration("Could not determine repository name, pass `--repository` formatted like `determinatesystems/flakehub-push`".to_string()))?;
+    };
+
+    let (upload_name, project_owner, project_name) =
+        determine_names(&cli.name.0, repository, cli.disable_rename_subgroups)?;
+    let repository = Repository {
+        full_name: repository.clone(),
+        owner: project_owner,
+        name: project_name,
+    };
+
+    let local_git_root = cli.resolve_local_git_root()?;
+    let local_rev_info = RevisionInfo::from_git_root(&local_git_root)?;
+
+    // "cli" and "git_ctx" are the user/env supplied info, augmented with data we might have fetched from github/gitlab apis
+    let (git_ctx, authenticator) = provider
+        .prepare(cli, client, &repository, local_rev_info)
+        .await?;
+
+    Ok(Prepared {
+        provider,
+        upload_name,
+        git_ctx,
+        authenticator,
+    })
 }
 
 pub(crate) fn determine_names(
@@ -3,12 +3,16 @@ use std::collections::HashSet;
 use color_eyre::eyre::{eyre, Context as _, Result};
 use flake_schemas::InspectOutput;
 
-use crate::cli::FlakeHubPushCli;
+use crate::cli::{FlakeHubPushCli, Telemetry};
+use crate::error::Error;
+use crate::evaluation::EvaluationScope;
 use crate::flake_info::FlakeMetadata;
 use crate::flakehub_client::Tarball;
 use crate::git_context::GitContext;
 use crate::github::graphql::{MAX_LABEL_LENGTH, MAX_NUM_TOTAL_LABELS};
-use crate::push_context::ExecutionEnvironment;
+use crate::nix::Nix;
+use crate::provider::Provider;
+use crate::timings::Phase;
 use crate::Visibility;
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -30,6 +34,10 @@ pub(crate) struct ReleaseMetadata {
     )]
     pub(crate) spdx_identifier: Option<spdx::Expression>,
 
+    // Which systems and outputs were evaluated and inspected, if that was restricted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) evaluation_scope: Option<EvaluationScope>,
+
     // A result of combining the labels specified on the CLI via the the GitHub Actions config
     // and the labels associated with the GitHub repo (they're called "topics" in GitHub parlance).
     pub(crate) labels: Vec<String>,
@@ -39,7 +47,8 @@ impl ReleaseMetadata {
     pub async fn new(
         cli: &FlakeHubPushCli,
         git_ctx: &GitContext,
-        exec_env: Option<&ExecutionEnvironment>,
+        provider: Option<&dyn Provider>,
+        telemetry: &Telemetry,
     ) -> Result<(Self, Tarball)> {
         let local_git_root = cli.resolve_local_git_root()?;
         let subdir = cli.subdir_from_git_root(&local_git_root)?;
@@ -47,20 +56,41 @@ impl ReleaseMetadata {
         // flake_dir is an absolute path of flake_root(aka git_root)/subdir
         let flake_dir = local_git_root.join(&subdir);
 
-        let flake_metadata = FlakeMetadata::from_dir(&flake_dir, cli.my_flake_is_too_big)
+        let nix = Nix::from_cli(cli, telemetry)?;
+
+        let flake_metadata = FlakeMetadata::from_dir(&nix, &flake_dir, telemetry)
             .await
             .wrap_err("Getting flake metadata")?;
         tracing::debug!("Got flake metadata: {:?}", flake_metadata);
 
+        let evaluation_scope = cli
+            .evaluation_scope(&nix, telemetry.workflow_commands)
+            .await?;
+
         // sanity checks
-        flake_metadata
-            .check_evaluates()
+        telemetry
+            .timings
+            .phase(
+                Phase::EvaluationCheck,
+                flake_metadata.check_evaluates(&evaluation_scope, cli.eval_jobs()),
+            )
             .await
             .wrap_err("failed to evaluate all system attrs of the flake")?;
-        flake_metadata
-            .check_lock_if_exists()
+        telemetry
+            .timings
+            .phase(Phase::LockCheck, flake_metadata.check_lock_if_exists())
             .await
             .wrap_err("failed to evaluate all system attrs of the flake")?;
+        if cli.flake_check {
+            telemetry
+                .timings
+                .phase(
+                    Phase::FlakeCheck,
+                    flake_metadata.check_flake(&evaluation_scope, cli.flake_check_build),
+                )
+                .await
+                .wrap_err("`nix flake check` failed")?;
+        }
 
         let Some(commit_count) = git_ctx.revision_info.commit_count else {
             return Err(eyre!("Could not determine commit count, this is normally determined via the `--git-root` argument or via the GitHub API"));
@@ -72,13 +102,26 @@ impl ReleaseMetadata {
             .and_then(serde_json::Value::as_str)
             .map(|s| s.to_string());
 
-        let flake_outputs = flake_metadata.outputs(cli.include_output_paths).await?;
+        let flake_outputs = if cli.my_flake_is_too_big {
+            InspectOutput::new()
+        } else {
+            telemetry
+                .timings
+                .phase(
+                    Phase::OutputInspection,
+                    flake_metadata.outputs(cli.include_output_paths, &evaluation_scope),
+                )
+                .await?
+        };
         tracing::debug!("Got flake outputs: {:?}", flake_outputs);
 
-        let readme = flake_metadata.get_readme_contents().await?;
+        let readme = telemetry
+            .timings
+            .phase(Phase::Readme, flake_metadata.get_readme_contents())
+            .await?;
 
         let Some(ref repository) = cli.repository.0 else {
-            return Err(eyre!("Could not determine repository name, pass `--repository` formatted like `determinatesystems/flakehub-push`"));
+            return Err(Error::MissingConfiguration("Could not determine repository name, pass `--repository` formatted like `determinatesystems/flakehub-push`".to_string()))?;
         };
 
         let (upload_name, _project_owner, _project_name) = crate::push_context::determine_names(
@@ -89,8 +132,8 @@ impl ReleaseMetadata {
 
         let visibility = cli.visibility()?;
 
-        let labels = if let Some(exec_env) = exec_env {
-            Self::merged_labels(cli, git_ctx, exec_env)
+        let labels = if provider.is_some() {
+            Self::merged_labels(cli, git_ctx, telemetry.workflow_commands)
         } else {
             Vec::new()
         };
@@ -110,11 +153,13 @@ impl ReleaseMetadata {
                 color_eyre::eyre::eyre!("Directory {:?} is not a valid UTF-8 string", subdir),
             )?),
             spdx_identifier: git_ctx.spdx_expression.clone(),
+            evaluation_scope: evaluation_scope.is_restricted().then_some(evaluation_scope),
             labels,
         };
 
-        let flake_tarball = flake_metadata
-            .flake_tarball()
+        let flake_tarball = telemetry
+            .timings
+            .phase_blocking(Phase::Tarball, || flake_metadata.flake_tarball())
             .wrap_err("Making release tarball")?;
 
         Ok((release_metadata, flake_tarball))
@@ -123,7 +168,7 @@ impl ReleaseMetadata {
     fn merged_labels(
         cli: &FlakeHubPushCli,
         git_ctx: &GitContext,
-        exec_env: &ExecutionEnvironment,
+        workflow_commands: bool,
     ) -> Vec<String> {
         let mut labels: HashSet<_> = cli
             .extra_labels
@@ -142,7 +187,7 @@ impl ReleaseMetadata {
             let message = "`extra-tags` is deprecated and will be removed in the future. Please use `extra-labels` instead.";
             tracing::warn!("{message}");
 
-            if matches!(&exec_env, ExecutionEnvironment::GitHub) {
+            if workflow_commands {
                 println!("::warning::{message}");
             }
 
@@ -153,7 +198,7 @@ impl ReleaseMetadata {
                     "Both `extra-tags` and `extra-labels` were set; `extra-tags` will be ignored.";
                 tracing::warn!("{message}");
 
-                if matches!(exec_env, ExecutionEnvironment::GitHub) {
+                if workflow_commands {
                     println!("::warning::{message}");
                 }
             }
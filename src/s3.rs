@@ -1,6 +1,7 @@
-use color_eyre::eyre::{eyre, Result, WrapErr};
+use color_eyre::eyre::{Result, WrapErr};
 use reqwest::header::HeaderMap;
 
+use crate::error::Error;
 use crate::flakehub_client::Tarball;
 
 pub async fn upload_release_to_s3(presigned_s3_url: String, tarball: Tarball) -> Result<()> {
@@ -27,7 +28,7 @@ pub async fn upload_release_to_s3(presigned_s3_url: String, tarball: Tarball) ->
         .body(tarball.bytes)
         .send()
         .await
-        .wrap_err("Sending tarball PUT")?;
+        .wrap_err(Error::S3Upload("Sending tarball PUT".to_string()))?;
 
     let tarball_put_response_status = tarball_put_response.status();
     tracing::trace!(
@@ -35,9 +36,9 @@ pub async fn upload_release_to_s3(presigned_s3_url: String, tarball: Tarball) ->
         "Got tarball PUT response"
     );
     if !tarball_put_response_status.is_success() {
-        return Err(eyre!(
+        return Err(Error::S3Upload(format!(
             "Got {tarball_put_response_status} status from PUT request"
-        ));
+        )))?;
     }
 
     Ok(())
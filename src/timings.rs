@@ -0,0 +1,159 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::Instrument;
+
+use crate::github_actions;
+
+/// The phases of a push, in the order they usually run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Phase {
+    Metadata,
+    Prefetch,
+    EvaluationCheck,
+    LockCheck,
+    FlakeCheck,
+    OutputInspection,
+    Readme,
+    Tarball,
+    Auth,
+    Stage,
+    Upload,
+    Publish,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::Metadata => f.write_str("metadata"),
+            Phase::Prefetch => f.write_str("prefetch"),
+            Phase::EvaluationCheck => f.write_str("evaluation check"),
+            Phase::LockCheck => f.write_str("lock check"),
+            Phase::FlakeCheck => f.write_str("flake check"),
+            Phase::OutputInspection => f.write_str("output inspection"),
+            Phase::Readme => f.write_str("README"),
+            Phase::Tarball => f.write_str("tarball"),
+            Phase::Auth => f.write_str("auth"),
+            Phase::Stage => f.write_str("stage"),
+            Phase::Upload => f.write_str("S3 upload"),
+            Phase::Publish => f.write_str("publish"),
+        }
+    }
+}
+
+struct Timing {
+    phase: Phase,
+    elapsed: Duration,
+    succeeded: bool,
+}
+
+/// How long each phase of a run took. Clones share what's recorded.
+#[derive(Clone, Default)]
+pub(crate) struct Timings {
+    recorded: Arc<Mutex<Vec<Timing>>>,
+    /// Whether to collapse each phase's log lines into a group, in GitHub Actions.
+    log_groups: bool,
+}
+
+impl Timings {
+    pub(crate) fn new(log_groups: bool) -> Self {
+        Self {
+            recorded: Default::default(),
+            log_groups,
+        }
+    }
+
+    /// Run `future` as `phase`, inside a span and log group named after it, recording how long it took.
+    pub(crate) async fn phase<T, E>(
+        &self,
+        phase: Phase,
+        future: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let span = tracing::info_span!("phase", %phase);
+        let _group = github_actions::group(&phase.to_string(), self.log_groups);
+        let start = Instant::now();
+        let result = future.instrument(span).await;
+        self.record(phase, start.elapsed(), result.is_ok());
+        result
+    }
+
+    /// Like [`Timings::phase`], for work which isn't async.
+    pub(crate) fn phase_blocking<T, E>(
+        &self,
+        phase: Phase,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let span = tracing::info_span!("phase", %phase);
+        let _group = github_actions::group(&phase.to_string(), self.log_groups);
+        let start = Instant::now();
+        let result = span.in_scope(f);
+        self.record(phase, start.elapsed(), result.is_ok());
+        result
+    }
+
+    fn record(&self, phase: Phase, elapsed: Duration, succeeded: bool) {
+        tracing::info!(
+            %phase,
+            elapsed_ms = elapsed.as_millis() as u64,
+            succeeded,
+            "Finished {phase} in {elapsed:.2?}"
+        );
+
+        if let Ok(mut timings) = self.recorded.lock() {
+            timings.push(Timing {
+                phase,
+                elapsed,
+                succeeded,
+            });
+        }
+    }
+
+    /// Log a table of how long each phase took, if any ran.
+    pub(crate) fn log_summary(&self) {
+        let Ok(timings) = self.recorded.lock() else {
+            return;
+        };
+        if timings.is_empty() {
+            return;
+        }
+
+        log_table(&timings);
+    }
+}
+
+/// Log `timings` as a table, summing up phases which ran more than once.
+fn log_table(timings: &[Timing]) {
+    // Phases like the evaluation check may run more than once, so sum them up in the order they first ran
+    let mut totals: Vec<(Phase, Duration, bool)> = Vec::new();
+    for timing in timings.iter() {
+        match totals
+            .iter_mut()
+            .find(|(phase, _, _)| *phase == timing.phase)
+        {
+            Some((_, elapsed, succeeded)) => {
+                *elapsed += timing.elapsed;
+                *succeeded &= timing.succeeded;
+            }
+            None => totals.push((timing.phase, timing.elapsed, timing.succeeded)),
+        }
+    }
+    let total: Duration = totals.iter().map(|(_, elapsed, _)| *elapsed).sum();
+
+    let mut table = format!("{:<28} {:>12}\n", "Phase", "Duration");
+    for (phase, elapsed, succeeded) in &totals {
+        let phase = if *succeeded {
+            phase.to_string()
+        } else {
+            format!("{phase} (failed)")
+        };
+        table.push_str(&format!("{phase:<28} {:>12}\n", format!("{elapsed:.2?}")));
+    }
+    table.push_str(&format!("{:<28} {:>12}", "total", format!("{total:.2?}")));
+
+    tracing::info!(
+        total_ms = total.as_millis() as u64,
+        "Phase timings:\n{table}"
+    );
+}
@@ -0,0 +1,175 @@
+use std::fmt::Write as _;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+/// The claims of an OIDC token which matter to FlakeHub, decoded *without* verifying the token.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct TokenClaims {
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub(crate) aud: Vec<String>,
+    pub(crate) iss: Option<String>,
+    pub(crate) sub: Option<String>,
+    /// GitHub and Forgejo's `owner/name`.
+    pub(crate) repository: Option<String>,
+    /// GitLab's `group/project`.
+    pub(crate) project_path: Option<String>,
+    #[serde(rename = "ref")]
+    pub(crate) git_ref: Option<String>,
+    pub(crate) exp: Option<f64>,
+}
+
+/// `aud` may be a single string or an array of them.
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(aud) => vec![aud],
+        OneOrMany::Many(aud) => aud,
+    })
+}
+
+impl TokenClaims {
+    /// Decode the payload of the JWT `token`. The signature is *not* checked, so this is only for diagnostics.
+    pub(crate) fn decode_unverified(token: &str) -> Result<Self> {
+        let mut parts = token.trim().split('.');
+        let (Some(_header), Some(payload), Some(_signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(eyre!(
+                "The token is not a JWT, which has three `.`-separated parts"
+            ));
+        };
+
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload.trim_end_matches('='))
+            .wrap_err("Decoding the token's payload as base64")?;
+        serde_json::from_slice(&payload).wrap_err("Decoding the token's payload as JSON")
+    }
+
+    /// How long until the token expires, which is zero if it already has, or `None` if it doesn't say.
+    pub(crate) fn expires_in(&self) -> Option<Duration> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+        let exp = Duration::try_from_secs_f64(self.exp?).ok()?;
+        Some(exp.saturating_sub(now))
+    }
+
+    /// Describe the claims next to what FlakeHub at `host` expects.
+    pub(crate) fn explain(&self, host: &url::Url) -> String {
+        let expected_audience = host.host_str().unwrap_or_default();
+        let unknown = || "(unset)".to_string();
+        let show = |claim: &Option<String>| {
+            claim
+                .as_deref()
+                .filter(|c| !c.is_empty())
+                .map_or_else(unknown, ToString::to_string)
+        };
+
+        let mut explanation = String::from("OIDC token claims (decoded, not verified):\n");
+
+        let audience = if self.aud.is_empty() {
+            unknown()
+        } else {
+            self.aud.join(", ")
+        };
+        let audience_note = if self.aud.iter().any(|aud| aud == expected_audience) {
+            "matches `--host`".to_string()
+        } else {
+            format!("`--host` expects `{expected_audience}`")
+        };
+        let _ = writeln!(explanation, "  audience:   {audience} ({audience_note})");
+        let _ = writeln!(explanation, "  issuer:     {}", show(&self.iss));
+        let _ = writeln!(explanation, "  subject:    {}", show(&self.sub));
+        let _ = writeln!(
+            explanation,
+            "  repository: {}",
+            show(
+                &self
+                    .repository
+                    .clone()
+                    .or_else(|| self.project_path.clone())
+            )
+        );
+        let _ = writeln!(explanation, "  ref:        {}", show(&self.git_ref));
+
+        let expiry = match self.exp {
+            Some(exp) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs_f64())
+                    .unwrap_or_default();
+                let remaining = exp - now;
+                if remaining >= 0.0 {
+                    format!("{exp:.0} (in {})", human_duration(remaining as u64))
+                } else {
+                    format!(
+                        "{exp:.0} (expired {} ago)",
+                        human_duration(-remaining as u64)
+                    )
+                }
+            }
+            None => unknown(),
+        };
+        let _ = write!(explanation, "  expires:    {expiry}");
+
+        explanation
+    }
+}
+
+/// Describe what's in `token` next to what FlakeHub at `host` expects, or why it couldn't be decoded.
+pub(crate) fn explain(token: &str, host: &url::Url) -> String {
+    if token.trim().split('.').count() != 3 {
+        return "The token is not a JWT, like a FlakeHub API token, so it has no claims to explain"
+            .to_string();
+    }
+    match TokenClaims::decode_unverified(token) {
+        Ok(claims) => claims.explain(host),
+        Err(e) => format!("Could not decode the OIDC token: {e}"),
+    }
+}
+
+pub(crate) fn human_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, (secs % 3600) / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+
+    use super::TokenClaims;
+
+    #[test]
+    fn decodes_unverified_claims() {
+        let payload = URL_SAFE_NO_PAD.encode(
+            r#"{"aud":["api.flakehub.com","other"],"iss":"https://token.actions.githubusercontent.com","repository":"foo/bar","ref":"refs/tags/v0.0.1","exp":1700000000}"#,
+        );
+        let token = format!("e30.{payload}.c2ln");
+
+        let claims = TokenClaims::decode_unverified(&token).unwrap();
+        assert_eq!(claims.aud, vec!["api.flakehub.com", "other"]);
+        assert_eq!(claims.repository.as_deref(), Some("foo/bar"));
+        assert_eq!(claims.git_ref.as_deref(), Some("refs/tags/v0.0.1"));
+        assert_eq!(claims.exp, Some(1700000000.0));
+
+        let explanation = claims.explain(&url::Url::parse("https://api.flakehub.com").unwrap());
+        assert!(explanation.contains("matches `--host`"), "{explanation}");
+        assert!(explanation.contains("expired"), "{explanation}");
+
+        let single = URL_SAFE_NO_PAD.encode(r#"{"aud":"flakehub-localhost"}"#);
+        let claims = TokenClaims::decode_unverified(&format!("e30.{single}.c2ln")).unwrap();
+        assert_eq!(claims.aud, vec!["flakehub-localhost"]);
+
+        assert!(TokenClaims::decode_unverified("not-a-jwt").is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use std::fmt::Write as _;
+use std::process::ExitCode;
+
+use color_eyre::eyre::{Result, WrapErr};
+
+use crate::{
+    api_token::ApiToken,
+    build_http_client,
+    cli::{FlakeHubPushCli, Telemetry},
+    error::Error,
+    flakehub_client::FlakeHubClient,
+    push_context::{determine_names, Prepared},
+    token_claims::{self, TokenClaims},
+};
+
+/// What FlakeHub says about the token flakehub-push would push with, along with what's known locally.
+#[derive(serde::Serialize)]
+struct WhoAmI {
+    host: url::Url,
+    environment: &'static str,
+    /// Unknown with an API token and no `--repository`, which aren't needed to check the token.
+    upload_name: Option<String>,
+    token: Token,
+    /// FlakeHub's response to `/token/status`, as-is.
+    flakehub: serde_json::Value,
+}
+
+#[derive(serde::Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+enum Token {
+    Oidc {
+        /// Decoded, not verified.
+        claims: TokenClaims,
+        expires_in_secs: Option<u64>,
+    },
+    ApiToken,
+}
+
+/// Acquire a token like a push would, and print what FlakeHub says about it.
+pub(crate) async fn run(
+    mut cli: FlakeHubPushCli,
+    json: bool,
+    telemetry: &Telemetry,
+) -> Result<ExitCode> {
+    let client = build_http_client().build()?;
+    let provider = crate::provider::detect(&cli)?;
+    let environment = provider.name();
+
+    // An API token doesn't depend on the repository, so it can be checked outside of a Git checkout
+    let (upload_name, authenticator) = if environment == ApiToken::NAME {
+        provider.backfill(&mut cli);
+        let upload_name = match &cli.repository.0 {
+            Some(repository) => {
+                Some(determine_names(&cli.name.0, repository, cli.disable_rename_subgroups)?.0)
+            }
+            None => None,
+        };
+        (upload_name, crate::api_token::authenticator(&cli).await?)
+    } else {
+        let Prepared {
+            upload_name,
+            authenticator,
+            ..
+        } = crate::push_context::prepare_with(provider, &mut cli, &client).await?;
+        (Some(upload_name), authenticator)
+    };
+
+    let fhclient = FlakeHubClient::new(cli.host.clone(), authenticator, telemetry)?;
+    let bearer_token = fhclient.bearer_token().await?;
+
+    let response = fhclient.token_status().await?;
+    let response = match FlakeHubClient::check_token_status(response).await {
+        Ok(response) => response,
+        Err(e) => {
+            if matches!(e, Error::Unauthorized(_)) {
+                tracing::error!("{}", token_claims::explain(&bearer_token, &cli.host));
+            }
+            return Err(e)?;
+        }
+    };
+    let body = response
+        .text()
+        .await
+        .wrap_err("Reading the token status response")?;
+    let flakehub: serde_json::Value = if body.trim().is_empty() {
+        serde_json::Value::Null
+    } else {
+        serde_json::from_str(&body).wrap_err("Decoding the token status response")?
+    };
+
+    let token = match TokenClaims::decode_unverified(&bearer_token) {
+        Ok(claims) => Token::Oidc {
+            expires_in_secs: claims.expires_in().map(|d| d.as_secs()),
+            claims,
+        },
+        Err(_) => Token::ApiToken,
+    };
+
+    let whoami = WhoAmI {
+        host: cli.host.clone(),
+        environment,
+        upload_name,
+        token,
+        flakehub,
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&whoami)?);
+    } else {
+        println!("{}", whoami.human());
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+impl WhoAmI {
+    fn human(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "FlakeHub:    {}", self.host);
+        let _ = writeln!(out, "Environment: {}", self.environment);
+        if let Some(upload_name) = &self.upload_name {
+            let _ = writeln!(out, "Flake:       {upload_name}");
+        }
+
+        match &self.token {
+            Token::Oidc {
+                claims,
+                expires_in_secs,
+            } => {
+                let expiry = match expires_in_secs {
+                    Some(secs) => {
+                        format!(", which expires in {}", token_claims::human_duration(*secs))
+                    }
+                    None => String::new(),
+                };
+                let _ = writeln!(out, "Token:       an OIDC token{expiry}");
+                let _ = writeln!(out, "{}", claims.explain(&self.host));
+            }
+            Token::ApiToken => {
+                let _ = writeln!(out, "Token:       a FlakeHub API token");
+            }
+        }
+
+        let _ = write!(out, "FlakeHub says:");
+        match &self.flakehub {
+            serde_json::Value::Null => {
+                let _ = write!(out, " the token is valid, with no details");
+            }
+            serde_json::Value::Object(fields) if fields.is_empty() => {
+                let _ = write!(out, " the token is valid, with no details");
+            }
+            serde_json::Value::Object(fields) => {
+                for (name, value) in fields {
+                    let _ = write!(out, "\n  {name}: {}", human_value(value));
+                }
+            }
+            other => {
+                let _ = write!(out, " {}", human_value(other));
+            }
+        }
+
+        out
+    }
+}
+
+/// A value from FlakeHub's response on one line, like lists of organizations or flakes.
+fn human_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "(unset)".to_string(),
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Array(values) if values.is_empty() => "(none)".to_string(),
+        serde_json::Value::Array(values)
+            if values.iter().all(|v| !v.is_object() && !v.is_array()) =>
+        {
+            values
+                .iter()
+                .map(human_value)
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{human_value, Token, WhoAmI};
+
+    #[test]
+    fn human_token_status() {
+        let flakehub = serde_json::json!({
+            "token_type": "api",
+            "user": "octocat",
+            "organizations": ["DeterminateSystems", "NixOS"],
+            "flakes": [],
+            "expires_at": null,
+            "scopes": [{ "org": "DeterminateSystems", "write": true }],
+        });
+        let whoami = WhoAmI {
+            host: url::Url::parse("https://api.flakehub.com").unwrap(),
+            environment: "token",
+            upload_name: None,
+            token: Token::ApiToken,
+            flakehub,
+        };
+
+        assert_eq!(
+            whoami.human(),
+            "\
+FlakeHub:    https://api.flakehub.com/
+Environment: token
+Token:       a FlakeHub API token
+FlakeHub says:
+  expires_at: (unset)
+  flakes: (none)
+  organizations: DeterminateSystems, NixOS
+  scopes: [{\"org\":\"DeterminateSystems\",\"write\":true}]
+  token_type: api
+  user: octocat"
+        );
+
+        assert_eq!(human_value(&serde_json::json!([1, "two"])), "1, two");
+        assert_eq!(human_value(&serde_json::json!(true)), "true");
+    }
+}